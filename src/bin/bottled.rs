@@ -1,8 +1,14 @@
+use bottled_shell::config::{Config, SessionType};
+use bottled_shell::doctor::{self, CheckState};
+use bottled_shell::exec;
+use bottled_shell::generator::{self, GeneratorDirs};
 use bottled_shell::systemd;
 use bottled_shell::shell;
+use bottled_shell::status::{self, BottleState};
+use bottled_shell::wslg;
 
 fn main() {
-    if let Err(_) = std::env::var("BOTTLED_SHELL_LOG") {
+    if std::env::var("BOTTLED_SHELL_LOG").is_err() {
         std::env::set_var("BOTTLED_SHELL_LOG", "info");
     }
     pretty_env_logger::init_custom_env("BOTTLED_SHELL_LOG");
@@ -19,17 +25,77 @@ fn main() {
             clap::SubCommand::with_name("is-running")
                 .about("Return 0 if systemd is running")
         )
+        .subcommand(
+            clap::SubCommand::with_name("status")
+                .about("Show the state of systemd, exit code reflects the state")
+                .arg(
+                    clap::Arg::with_name("json")
+                        .long("json")
+                        .help("Print in JSON format")
+                )
+        )
         .subcommand(
             clap::SubCommand::with_name("start")
                 .about("Start systemd in a Linux namespace(mount, pid)")
         )
         .subcommand(
             clap::SubCommand::with_name("stop")
-                .about("Stop systemd")
+                .about("Stop systemd, exit code is 2 if systemd had to be killed, 3 if not waited for from inside")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("restart")
+                .about("Stop systemd, wait for it to exit, and start it again")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("reload")
+                .about("Update preserved environment variables, and reload systemd")
+        )
+        .subcommand(
+            clap::SubCommand::with_name("ns-path")
+                .about("Print paths of the namespaces of systemd, e.g. for nsenter")
+                .arg(
+                    clap::Arg::with_name("namespace")
+                        .help("Print only the path of NAMESPACE")
+                        .possible_values(&["pid", "mnt", "uts", "ipc", "cgroup"])
+                )
+        )
+        .subcommand(
+            clap::SubCommand::with_name("doctor")
+                .about("Check for known problems, exit code is 1 if any check failed")
+                .arg(
+                    clap::Arg::with_name("json")
+                        .long("json")
+                        .help("Print in JSON format")
+                )
+        )
+        .subcommand(
+            clap::SubCommand::with_name("generator")
+                .about("Write units describing WSL, run by systemd as a generator")
+                .arg(
+                    clap::Arg::with_name("normal")
+                        .required(true)
+                )
+                .arg(
+                    clap::Arg::with_name("early")
+                        .required(true)
+                )
+                .arg(
+                    clap::Arg::with_name("late")
+                        .required(true)
+                )
+        )
+        .subcommand(
+            clap::SubCommand::with_name("wslg")
+                .about("Link WSLg sockets into the runtime directory of UID, run inside the bottle by root")
+                .arg(
+                    clap::Arg::with_name("uid")
+                        .value_name("UID")
+                        .required(true)
+                )
         )
         .subcommand(
             clap::SubCommand::with_name("shell")
-                .about("Start a shell inside systemd-enabled namespace using machinectl-shell")
+                .about("Start a login shell inside systemd-enabled namespace")
                 .arg(
                     clap::Arg::with_name("shell")
                         .short("s")
@@ -38,10 +104,43 @@ fn main() {
                         .help("Specify interactive shell")
                         .takes_value(true)
                 )
+                .arg(
+                    clap::Arg::with_name("session")
+                        .long("session")
+                        .value_name("TYPE")
+                        .help("Specify how to enter the namespace")
+                        .possible_values(&["auto", "machinectl", "pam"])
+                        .takes_value(true)
+                )
                 .arg(
                     clap::Arg::with_name("shell-options")
                         .raw(true)
                 )
+        )
+        .subcommand(
+            clap::SubCommand::with_name("exec")
+                .about("Run a command inside systemd-enabled namespace without a login session")
+                .arg(
+                    clap::Arg::with_name("user")
+                        .short("u")
+                        .long("user")
+                        .value_name("USER")
+                        .help("Run the command as USER")
+                        .takes_value(true)
+                )
+                .arg(
+                    clap::Arg::with_name("cwd")
+                        .short("C")
+                        .long("cwd")
+                        .value_name("DIR")
+                        .help("Run the command in DIR")
+                        .takes_value(true)
+                )
+                .arg(
+                    clap::Arg::with_name("command")
+                        .raw(true)
+                        .required(true)
+                )
         );
     let matches = app.get_matches_from_safe_borrow(std::env::args_os()).unwrap_or_else(|e| {
         if e.use_stderr() {
@@ -51,9 +150,14 @@ fn main() {
         e.exit()
     });
 
+    let mut config = Config::load().unwrap_or_else(|e| {
+        log::error!("{}", e);
+        std::process::exit(1);
+    });
+
     match matches.subcommand() {
         ("is-inside", _) => {
-            if systemd::is_associated_with_systemd() {
+            if systemd::is_associated_with_systemd(&config) {
                 log::info!("is-inside=true");
                 std::process::exit(libc::EXIT_SUCCESS);
             } else {
@@ -62,29 +166,138 @@ fn main() {
             }
         }
         ("is-running", _) => {
-            if systemd::is_associated_with_systemd() {
+            if systemd::is_associated_with_systemd(&config) {
                 log::info!("is-running=true");
                 std::process::exit(libc::EXIT_SUCCESS);
-            } else if let Ok(Some(pid)) = systemd::get_systemd_pid() {
+            } else if let Ok(Some(pid)) = systemd::get_systemd_pid(&config) {
                 log::info!("is-running=true, PID={}", pid);
                 std::process::exit(libc::EXIT_SUCCESS);
+            } else if let Ok(true) = systemd::is_pid_file_stale(&config) {
+                log::info!("is-running=false, stale PID file");
+                std::process::exit(libc::EXIT_FAILURE);
             } else {
                 log::info!("is-running=false");
                 std::process::exit(libc::EXIT_FAILURE);
             }
         }
+        ("status", Some(m)) => {
+            match status::get_status(&config) {
+                Ok(s) => {
+                    if m.is_present("json") {
+                        println!("{}", serde_json::to_string_pretty(&s).unwrap());
+                    } else {
+                        println!("{}", s);
+                    }
+                    std::process::exit(s.state.exit_code());
+                }
+                Err(e) => {
+                    log::error!("{}", e);
+                    std::process::exit(BottleState::Unknown.exit_code());
+                }
+            }
+        }
         ("start", _) => {
-            systemd::start_systemd().unwrap();
+            if let Err(e) = systemd::start_systemd(&config) {
+                log::error!("{}", e);
+                std::process::exit(libc::EXIT_FAILURE);
+            }
         }
         ("stop", _) => {
-            systemd::stop_systemd().unwrap();
+            match systemd::stop_systemd(&config) {
+                Ok(r) => std::process::exit(r.exit_code()),
+                Err(e) => {
+                    log::error!("{}", e);
+                    std::process::exit(libc::EXIT_FAILURE);
+                }
+            }
+        }
+        ("restart", _) => {
+            if let Err(e) = systemd::restart_systemd(&config) {
+                log::error!("{}", e);
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+        }
+        ("reload", _) => {
+            if let Err(e) = systemd::reload_systemd(&config) {
+                log::error!("{}", e);
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+        }
+        ("ns-path", Some(m)) => {
+            let handles = systemd::get_namespace_handles(&config).unwrap_or_else(|e| {
+                log::error!("{}", e);
+                std::process::exit(libc::EXIT_FAILURE);
+            });
+            match m.value_of("namespace") {
+                Some(ns) => match handles.iter().find(|(n, _)| *n == ns) {
+                    Some((_, path)) => println!("{}", path),
+                    None => {
+                        log::error!("no handle of {} namespace", ns);
+                        std::process::exit(libc::EXIT_FAILURE);
+                    }
+                },
+                None => {
+                    for (ns, path) in handles {
+                        println!("{} {}", ns, path);
+                    }
+                }
+            }
+        }
+        ("doctor", Some(m)) => {
+            let checks = doctor::run_checks(&config);
+            if m.is_present("json") {
+                println!("{}", serde_json::to_string_pretty(&checks).unwrap());
+            } else {
+                for c in &checks {
+                    println!("{}", c);
+                }
+            }
+            if checks.iter().any(|c| c.state == CheckState::Failed) {
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+        }
+        ("generator", Some(m)) => {
+            // systemd runs generators as root, output directories given by
+            // anyone else may hold symlinks to files of root
+            if !nix::unistd::getuid().is_root() {
+                log::error!("only root is allowed to run the generator");
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+            let dirs = GeneratorDirs {
+                normal: m.value_of("normal").unwrap().into(),
+                early: m.value_of("early").unwrap().into(),
+                late: m.value_of("late").unwrap().into(),
+            };
+            let env = generator::inspect(&config);
+            log::debug!("WSL environment: {:?}", env);
+            if let Err(e) = generator::generate(&config, &env, &dirs) {
+                log::error!("{}", e);
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+        }
+        ("wslg", Some(m)) => {
+            if !nix::unistd::getuid().is_root() || !systemd::is_associated_with_systemd(&config) {
+                log::error!("only root is allowed to set up WSLg, inside the bottle");
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+            let uid = m.value_of("uid").unwrap().parse::<libc::uid_t>().unwrap_or_else(|_| {
+                log::error!("invalid UID {}", m.value_of("uid").unwrap());
+                std::process::exit(libc::EXIT_FAILURE);
+            });
+            if let Err(e) = wslg::setup_user(&config, nix::unistd::Uid::from_raw(uid)) {
+                log::error!("{}", e);
+                std::process::exit(libc::EXIT_FAILURE);
+            }
         }
         ("shell", Some(m)) => {
             let mut shell = "bash";
             if let Some(s) = m.value_of("shell") {
-                shell = s.clone();
+                shell = s;
             };
             log::debug!("specified shell: {}", shell);
+            if let Some(s) = m.value_of("session") {
+                config.shell.session = s.parse::<SessionType>().unwrap();
+            }
 
             let bottled_shell = if let Some((c, _)) = clap::crate_name!().rsplit_once('-') {
                 format!("{}-{}", c, shell)
@@ -103,13 +316,40 @@ fn main() {
                 args.push(v);
             }
 
-            if !systemd::is_associated_with_systemd() && None == systemd::get_systemd_pid().unwrap() {
-                log::trace!("starting bottled systemd");
-                systemd::start_systemd().unwrap();
+            // waits for a start in progress, e.g. by another tab restored at once
+            if !systemd::is_associated_with_systemd(&config) {
+                log::trace!("starting bottled systemd unless running");
+                if let Err(e) = systemd::ensure_systemd(&config) {
+                    log::error!("{}", e);
+                    std::process::exit(libc::EXIT_FAILURE);
+                }
             }
 
             log::trace!("starting login shell: {}", shell);
-            shell::launch_login_shell(&bottled_shell_path, &shell.to_string(), &args).unwrap();
+            if let Err(e) = shell::launch_login_shell(&config, &bottled_shell_path, shell, &args) {
+                log::error!("{}", e);
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+        }
+        ("exec", Some(m)) => {
+            let args = m.values_of_lossy("command").unwrap();
+
+            // waits for a start in progress, e.g. by another tab restored at once
+            if !systemd::is_associated_with_systemd(&config) {
+                log::trace!("starting bottled systemd unless running");
+                if let Err(e) = systemd::ensure_systemd(&config) {
+                    log::error!("{}", e);
+                    std::process::exit(126);
+                }
+            }
+
+            match exec::run_command(&config, m.value_of("user"), m.value_of("cwd"), &args) {
+                Ok(code) => std::process::exit(code),
+                Err(e) => {
+                    log::error!("{}", e);
+                    std::process::exit(126);
+                }
+            }
         }
         _ => unreachable!()
     }
@@ -0,0 +1,251 @@
+//! binfmt_misc registration of the Windows interop of WSL.
+//!
+//! `systemd-binfmt.service` unregisters every entry while the bottle boots,
+//! and binfmt_misc is shared with WSL, so `cmd.exe` stops working everywhere.
+//! The registration is recorded before booting, and restored afterwards.
+
+use crate::config::Config;
+
+#[derive(thiserror::Error, Debug)]
+pub enum BinfmtError {
+    #[error("malformed binfmt_misc entry {0}: {1}")]
+    MalformedEntry(String, String),
+
+    #[error("binfmt_misc not mounted at {0}")]
+    NotMounted(String),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// An entry of binfmt_misc, as shown in `<binfmt_misc>/<name>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinfmtEntry {
+    pub name: String,
+    pub enabled: bool,
+    pub interpreter: String,
+    pub flags: String,
+    pub offset: u64,
+    /// Magic bytes in hex, for entries matching by magic.
+    pub magic: Option<String>,
+    pub mask: Option<String>,
+    /// File extension, for entries matching by extension.
+    pub extension: Option<String>,
+}
+
+impl BinfmtEntry {
+    pub fn parse(name: &str, content: &str) -> Result<BinfmtEntry, BinfmtError> {
+        let malformed = |m: &str| BinfmtError::MalformedEntry(name.to_string(), m.to_string());
+        let mut entry = BinfmtEntry {
+            name: name.to_string(),
+            enabled: false,
+            interpreter: String::new(),
+            flags: String::new(),
+            offset: 0,
+            magic: None,
+            mask: None,
+            extension: None,
+        };
+        for line in content.lines() {
+            match line.split_once(' ') {
+                None if line == "enabled" => entry.enabled = true,
+                None if line == "disabled" => entry.enabled = false,
+                Some(("interpreter", v)) => entry.interpreter = v.to_string(),
+                Some(("flags:", v)) => entry.flags = v.trim().to_string(),
+                None if line == "flags:" => (),
+                Some(("offset", v)) => entry.offset = v.parse().map_err(|_| malformed(line))?,
+                Some(("magic", v)) => entry.magic = Some(v.to_string()),
+                Some(("mask", v)) => entry.mask = Some(v.to_string()),
+                Some(("extension", v)) => entry.extension = Some(v.trim_start_matches('.').to_string()),
+                _ => log::debug!("unknown line in binfmt_misc entry {}: {}", name, line),
+            }
+        }
+        if entry.interpreter.is_empty() {
+            return Err(malformed("no interpreter"));
+        }
+        if entry.magic.is_none() && entry.extension.is_none() {
+            return Err(malformed("neither magic nor extension"));
+        }
+        Ok(entry)
+    }
+
+    /// Read the entry `name` under the binfmt_misc mount `dir`, `None` when
+    /// it is not registered.
+    pub fn read(dir: &str, name: &str) -> Result<Option<BinfmtEntry>, BinfmtError> {
+        match std::fs::read_to_string(format!("{}/{}", dir, name)) {
+            Ok(content) => Ok(Some(BinfmtEntry::parse(name, &content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(BinfmtError::IOError(e)),
+        }
+    }
+
+    /// Line written to `<binfmt_misc>/register`, `:name:type:offset:magic:mask:interpreter:flags`.
+    pub fn registration(&self) -> String {
+        let escape = |hex: &str| {
+            hex.as_bytes()
+                .chunks(2)
+                .map(|c| format!("\\x{}", String::from_utf8_lossy(c)))
+                .collect::<String>()
+        };
+        match &self.magic {
+            Some(magic) => format!(
+                ":{}:M:{}:{}:{}:{}:{}",
+                self.name,
+                self.offset,
+                escape(magic),
+                self.mask.as_deref().map(escape).unwrap_or_default(),
+                self.interpreter,
+                self.flags
+            ),
+            None => format!(
+                ":{}:E::{}::{}:{}",
+                self.name,
+                self.extension.as_deref().unwrap_or_default(),
+                self.interpreter,
+                self.flags
+            ),
+        }
+    }
+
+    pub fn register(&self, dir: &str) -> Result<(), BinfmtError> {
+        if std::fs::metadata(format!("{}/register", dir)).is_err() {
+            return Err(BinfmtError::NotMounted(dir.to_string()));
+        }
+        log::trace!("registering binfmt_misc entry: {}", self.registration());
+        std::fs::write(format!("{}/register", dir), self.registration())?;
+        Ok(())
+    }
+}
+
+fn get_record_file(config: &Config) -> String {
+    format!("{}/{}.binfmt", config.bottle.run_dir, config.interop.binfmt_name)
+}
+
+/// The interop entry recorded by an earlier start, if any.
+pub fn read_recorded_interop(config: &Config) -> Result<Option<BinfmtEntry>, BinfmtError> {
+    let path = get_record_file(config);
+    match std::fs::read_to_string(&path) {
+        Ok(content) => Ok(Some(BinfmtEntry::parse(&config.interop.binfmt_name, &content)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(BinfmtError::IOError(e)),
+    }
+}
+
+/// Record the interop entry, kept across restarts since the entry may
+/// already be gone the next time.
+pub fn record_interop(config: &Config) -> Result<(), BinfmtError> {
+    let interop = &config.interop;
+    let path = format!("{}/{}", interop.binfmt_misc, interop.binfmt_name);
+    match std::fs::read_to_string(&path) {
+        Ok(content) => {
+            BinfmtEntry::parse(&interop.binfmt_name, &content)?;
+            std::fs::create_dir_all(&config.bottle.run_dir)?;
+            std::fs::write(get_record_file(config), content)?;
+            log::trace!("recorded {}", path);
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => log::debug!("{} not registered", path),
+        Err(e) => return Err(BinfmtError::IOError(e)),
+    }
+    Ok(())
+}
+
+/// Register the recorded interop entry again if it is gone, returns whether
+/// it was restored.
+pub fn restore_interop(config: &Config) -> Result<bool, BinfmtError> {
+    let interop = &config.interop;
+    if BinfmtEntry::read(&interop.binfmt_misc, &interop.binfmt_name)?.is_some() {
+        return Ok(false);
+    }
+    match read_recorded_interop(config)? {
+        Some(entry) => {
+            entry.register(&interop.binfmt_misc)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTEROP: &str = "enabled\ninterpreter /init\nflags: PF\noffset 0\nmagic 4d5a\n";
+
+    #[test]
+    fn test_parse_magic() {
+        let entry = BinfmtEntry::parse("WSLInterop", INTEROP).unwrap();
+        assert!(entry.enabled);
+        assert_eq!(entry.interpreter, "/init");
+        assert_eq!(entry.flags, "PF");
+        assert_eq!(entry.magic.as_deref(), Some("4d5a"));
+        assert_eq!(entry.registration(), ":WSLInterop:M:0:\\x4d\\x5a::/init:PF");
+
+        let content = "disabled\ninterpreter /usr/bin/qemu\nflags: \noffset 2\nmagic 7f45\nmask fffe\n";
+        let entry = BinfmtEntry::parse("qemu", content).unwrap();
+        assert!(!entry.enabled);
+        assert_eq!(entry.registration(), ":qemu:M:2:\\x7f\\x45:\\xff\\xfe:/usr/bin/qemu:");
+    }
+
+    #[test]
+    fn test_parse_extension() {
+        let content = "enabled\ninterpreter /usr/bin/wine\nflags:\nextension .exe\n";
+        let entry = BinfmtEntry::parse("wine", content).unwrap();
+        assert_eq!(entry.extension.as_deref(), Some("exe"));
+        assert_eq!(entry.registration(), ":wine:E::exe::/usr/bin/wine:");
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let contents = [
+            "enabled\nmagic 4d5a\n",
+            "enabled\ninterpreter /init\n",
+            "interpreter /init\noffset x\nmagic 4d5a\n",
+        ];
+        for content in contents {
+            assert!(matches!(
+                BinfmtEntry::parse("WSLInterop", content),
+                Err(BinfmtError::MalformedEntry(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_restore_interop() {
+        let binfmt_misc = tempfile::tempdir().unwrap();
+        let run_dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.bottle.run_dir = run_dir.path().join("bottled-shell").to_string_lossy().to_string();
+        config.interop.binfmt_misc = binfmt_misc.path().to_string_lossy().to_string();
+        let entry = binfmt_misc.path().join("WSLInterop");
+        let register = binfmt_misc.path().join("register");
+
+        // nothing recorded yet
+        assert!(!restore_interop(&config).unwrap());
+
+        std::fs::write(&entry, INTEROP).unwrap();
+        std::fs::write(&register, "").unwrap();
+        record_interop(&config).unwrap();
+        assert_eq!(
+            read_recorded_interop(&config).unwrap(),
+            Some(BinfmtEntry::parse("WSLInterop", INTEROP).unwrap())
+        );
+
+        // still registered
+        assert!(!restore_interop(&config).unwrap());
+        assert_eq!(std::fs::read_to_string(&register).unwrap(), "");
+
+        // removed by systemd-binfmt, the record is kept from the last start
+        std::fs::remove_file(&entry).unwrap();
+        record_interop(&config).unwrap();
+        assert!(restore_interop(&config).unwrap());
+        assert_eq!(std::fs::read_to_string(&register).unwrap(), ":WSLInterop:M:0:\\x4d\\x5a::/init:PF");
+    }
+
+    #[test]
+    fn test_register_not_mounted() {
+        let binfmt_misc = tempfile::tempdir().unwrap();
+        let dir = binfmt_misc.path().to_string_lossy().to_string();
+        let entry = BinfmtEntry::parse("WSLInterop", INTEROP).unwrap();
+        assert!(matches!(entry.register(&dir), Err(BinfmtError::NotMounted(_))));
+    }
+}
@@ -0,0 +1,449 @@
+//! cgroup v2 hierarchy of the bottle.
+//!
+//! systemd in the bottle runs in a dedicated subtree, and gets a cgroup2
+//! mount at `/sys/fs/cgroup` when WSL does not use the unified layout.
+
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::pidfd::PidFd;
+
+pub static CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+#[derive(thiserror::Error, Debug)]
+pub enum CgroupError {
+    #[error("cgroups are unusable, {0}")]
+    Unusable(String),
+
+    #[error("cgroup {0} still has processes")]
+    Busy(String),
+
+    #[error("invalid cgroup limit {0}")]
+    InvalidLimit(String),
+
+    #[error("failed to set {0} to {1}: {2}")]
+    LimitFailed(String, String, std::io::Error),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    NixErrno(#[from] nix::errno::Errno),
+}
+
+/// Layout of the cgroup hierarchies outside the bottle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupLayout {
+    /// cgroup2 only, mounted at `/sys/fs/cgroup`.
+    Unified,
+    /// cgroup v1 controllers, and cgroup2 mounted elsewhere, e.g.
+    /// `/sys/fs/cgroup/unified`.
+    Hybrid,
+    /// cgroup v1 controllers only.
+    Legacy,
+}
+
+/// Mount points of cgroup2 hierarchies rooted at the top, from the content
+/// of `/proc/<pid>/mountinfo`.
+pub fn find_cgroup2_mounts(mountinfo: &str) -> Vec<String> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (mount, fs) = line.split_once(" - ")?;
+            let mut mount = mount.split(' ');
+            let root = mount.nth(3)?;
+            let mount_point = mount.next()?;
+            let fs_type = fs.split(' ').next()?;
+            if fs_type == "cgroup2" && root == "/" {
+                Some(mount_point.replace("\\040", " "))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Detect the layout from the filesystem at `root`, and the cgroup2 mount
+/// points.
+pub fn detect_layout(root: &str, cgroup2_mounts: &[String]) -> Result<CgroupLayout, CgroupError> {
+    use nix::sys::statfs::CGROUP2_SUPER_MAGIC;
+
+    let fs = nix::sys::statfs::statfs(root)
+        .map_err(|e| CgroupError::Unusable(format!("{} not available: {}", root, e)))?;
+    if fs.filesystem_type() == CGROUP2_SUPER_MAGIC {
+        Ok(CgroupLayout::Unified)
+    } else if !cgroup2_mounts.is_empty() {
+        Ok(CgroupLayout::Hybrid)
+    } else {
+        Ok(CgroupLayout::Legacy)
+    }
+}
+
+fn is_cgroup2_supported() -> Result<bool, CgroupError> {
+    let filesystems = std::fs::read_to_string("/proc/filesystems")?;
+    Ok(filesystems.lines().any(|l| l.split_whitespace().last() == Some("cgroup2")))
+}
+
+/// Layout of the cgroup hierarchies of the calling process.
+pub fn get_layout() -> Result<CgroupLayout, CgroupError> {
+    let mounts = find_cgroup2_mounts(&std::fs::read_to_string("/proc/self/mountinfo")?);
+    let layout = detect_layout(CGROUP_ROOT, &mounts)?;
+    log::trace!("cgroup layout: {:?}, cgroup2 mounted at {:?}", layout, mounts);
+    Ok(layout)
+}
+
+fn get_fallback_mount(config: &Config) -> String {
+    format!("{}/cgroup", config.bottle.run_dir)
+}
+
+/// A cgroup2 mount outside the bottle, mounting one under the runtime
+/// directory when there is none.
+fn get_cgroup2_mount(config: &Config) -> Result<String, CgroupError> {
+    use nix::mount::MsFlags;
+
+    let mounts = find_cgroup2_mounts(&std::fs::read_to_string("/proc/self/mountinfo")?);
+    if let Some(m) = mounts.into_iter().next() {
+        return Ok(m);
+    }
+
+    if !is_cgroup2_supported()? {
+        return Err(CgroupError::Unusable("cgroup2 not supported by the kernel".to_string()));
+    }
+    let path = get_fallback_mount(config);
+    std::fs::create_dir_all(&path)?;
+    log::trace!("mounting cgroup2 at {}", path);
+    nix::mount::mount(
+        Some("cgroup2"),
+        path.as_str(),
+        Some("cgroup2"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None as Option<&str>,
+    )
+    .map_err(|e| CgroupError::Unusable(format!("failed to mount cgroup2: {}", e)))?;
+    Ok(path)
+}
+
+/// Path of the cgroup of the bottle outside of it, `None` when there is no
+/// cgroup2 mount.
+pub fn get_bottle_cgroup(config: &Config) -> Option<String> {
+    let mounts = find_cgroup2_mounts(&std::fs::read_to_string("/proc/self/mountinfo").ok()?);
+    let path = format!("{}/{}", mounts.first()?, config.cgroup.path.trim_matches('/'));
+    std::fs::metadata(&path).ok().map(|_| path)
+}
+
+fn is_populated(path: &str) -> Result<bool, CgroupError> {
+    let events = std::fs::read_to_string(format!("{}/cgroup.events", path))?;
+    Ok(events.lines().any(|l| l == "populated 1"))
+}
+
+/// Enable the controllers available in the cgroup at `path` for its children.
+fn enable_controllers(path: &std::path::Path) -> Result<(), CgroupError> {
+    let controllers = std::fs::read_to_string(path.join("cgroup.controllers"))?;
+    for c in controllers.split_whitespace() {
+        if let Err(e) = std::fs::write(path.join("cgroup.subtree_control"), format!("+{}", c)) {
+            log::warn!("failed to enable {} controller in {}: {}", c, path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Create the cgroup of the bottle, returns its path outside the bottle.
+///
+/// Controllers available in its ancestors are enabled for it, so they can be
+/// delegated to the bottle.
+pub fn prepare_bottle_cgroup(config: &Config) -> Result<String, CgroupError> {
+    let mount = get_cgroup2_mount(config)?;
+    let relative = config.cgroup.path.trim_matches('/');
+    if relative.is_empty() {
+        return Err(CgroupError::Unusable("the cgroup of the bottle can not be the root".to_string()));
+    }
+    let path = format!("{}/{}", mount, relative);
+
+    if std::fs::metadata(&path).is_ok() {
+        if is_populated(&path)? {
+            return Err(CgroupError::Busy(path));
+        }
+    } else {
+        log::trace!("creating cgroup {}", path);
+        std::fs::create_dir_all(&path)
+            .map_err(|e| CgroupError::Unusable(format!("failed to create {}: {}", path, e)))?;
+    }
+
+    // from the top, controllers only reach a cgroup enabled in every ancestor
+    let ancestors: Vec<&std::path::Path> = std::path::Path::new(&path)
+        .ancestors()
+        .skip(1)
+        .take_while(|a| a.starts_with(&mount))
+        .collect();
+    for ancestor in ancestors.into_iter().rev() {
+        enable_controllers(ancestor)?;
+    }
+    let controllers = std::fs::read_to_string(format!("{}/cgroup.controllers", path))?;
+    if controllers.trim().is_empty() {
+        log::warn!("no controllers available in cgroup2, resources of the bottle can not be limited");
+    } else {
+        log::trace!("controllers of {}: {}", path, controllers.trim());
+    }
+
+    apply_limits(config, &path)?;
+    Ok(path)
+}
+
+/// Interface file holding the usage limited by a file, e.g. `memory.current`
+/// for `memory.max`, or `cpu.stat` for `cpu.weight`.
+fn get_usage_file(limit: &str) -> Option<String> {
+    let (controller, _) = limit.split_once('.')?;
+    let (prefix, _) = limit.rsplit_once('.')?;
+    match controller {
+        "cpu" | "io" => Some(format!("{}.stat", controller)),
+        _ => Some(format!("{}.current", prefix)),
+    }
+}
+
+/// Check the name of an interface file, limits must not escape the cgroup,
+/// nor move processes.
+fn check_limit_name(name: &str) -> Result<(), CgroupError> {
+    let valid = name.split_once('.').is_some_and(|(c, f)| !c.is_empty() && !f.is_empty())
+        && !name.contains('/')
+        && !name.starts_with("cgroup.");
+    if !valid {
+        return Err(CgroupError::InvalidLimit(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Write the limits in config to the cgroup at `path`.
+fn apply_limits(config: &Config, path: &str) -> Result<(), CgroupError> {
+    for (name, value) in &config.cgroup.limits {
+        check_limit_name(name)?;
+        let file = format!("{}/{}", path, name);
+        if std::fs::metadata(&file).is_err() {
+            // WSL attaches every controller to a cgroup v1 hierarchy then
+            if get_layout()? != CgroupLayout::Unified {
+                return Err(CgroupError::Unusable(format!(
+                    "{} can not apply, cgroup v1 holds the controllers and none is available in cgroup2", name
+                )));
+            }
+            let controller = name.split_once('.').unwrap().0;
+            return Err(CgroupError::Unusable(format!(
+                "{} not available, the {} controller is not enabled in cgroup2", name, controller
+            )));
+        }
+        log::trace!("setting {} to {}", file, value);
+        std::fs::write(&file, value.to_string())
+            .map_err(|e| CgroupError::LimitFailed(name.clone(), value.to_string(), e))?;
+    }
+    Ok(())
+}
+
+/// A limit of the cgroup of the bottle, and the usage it limits.
+#[derive(Serialize, Debug, Clone)]
+pub struct CgroupLimit {
+    pub name: String,
+    pub value: String,
+    /// Content of the usage file, e.g. `memory.current`, or the `usage_usec`
+    /// line of `cpu.stat`.
+    pub usage: Option<String>,
+}
+
+/// Current limits of the cgroup at `path`, for the ones in config.
+pub fn get_limits(config: &Config, path: &str) -> Vec<CgroupLimit> {
+    let read = |f: &str| std::fs::read_to_string(format!("{}/{}", path, f)).ok();
+    config
+        .cgroup
+        .limits
+        .keys()
+        .filter(|name| check_limit_name(name).is_ok())
+        .map(|name| {
+            let usage = get_usage_file(name).and_then(|f| read(&f)).map(|u| {
+                // stat files have one `key value` per line
+                match u.lines().find(|l| l.starts_with("usage_usec ")) {
+                    Some(l) => l.to_string(),
+                    None => u.trim().to_string(),
+                }
+            });
+            CgroupLimit {
+                name: name.clone(),
+                value: read(name).unwrap_or_default().trim().to_string(),
+                usage,
+            }
+        })
+        .collect()
+}
+
+/// Move the calling process into the cgroup at `path`.
+pub fn enter_cgroup(path: &str) -> Result<(), CgroupError> {
+    std::fs::write(format!("{}/cgroup.procs", path), "0")?;
+    Ok(())
+}
+
+/// Cgroup of the commands run by `bottled exec`, a leaf under the one of the
+/// bottle created when missing, since the controllers systemd enables for
+/// the cgroup of the bottle keep it from holding processes itself.
+pub fn get_exec_cgroup(config: &Config) -> Result<String, CgroupError> {
+    let path = match get_bottle_cgroup(config) {
+        Some(p) => format!("{}/bottled-exec", p),
+        None => return Err(CgroupError::Unusable("the cgroup of the bottle does not exist".to_string())),
+    };
+    match std::fs::create_dir(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(e.into()),
+        _ => Ok(path),
+    }
+}
+
+/// Wait until no process is left in the cgroup at `path`, returns `false`
+/// on timeout.
+fn wait_unpopulated(path: &str, timeout: std::time::Duration) -> Result<bool, CgroupError> {
+    let start = std::time::Instant::now();
+    let interval = std::time::Duration::from_millis(100);
+    while is_populated(path)? {
+        if start.elapsed() >= timeout {
+            return Ok(false);
+        }
+        std::thread::sleep(interval);
+    }
+    Ok(true)
+}
+
+/// Kill every process left in the cgroup of the bottle, e.g. children of a
+/// failed start, returns `false` when some are still alive after `timeout`.
+pub fn kill_bottle_cgroup(config: &Config, timeout: std::time::Duration) -> Result<bool, CgroupError> {
+    let path = match get_bottle_cgroup(config) {
+        Some(p) => p,
+        None => return Ok(true),
+    };
+    if !is_populated(&path)? {
+        return Ok(true);
+    }
+    log::debug!("killing processes left in {}", path);
+    // cgroup.kill is available since Linux 5.14
+    let kill = format!("{}/cgroup.kill", path);
+    if std::fs::metadata(&kill).is_ok() {
+        std::fs::write(&kill, "1")?;
+        return wait_unpopulated(&path, timeout);
+    }
+
+    // processes are killed one by one otherwise, again until none is left
+    // since they may fork meanwhile
+    let start = std::time::Instant::now();
+    let interval = std::time::Duration::from_millis(100);
+    while is_populated(&path)? {
+        if start.elapsed() >= timeout {
+            return Ok(false);
+        }
+        kill_procs(std::path::Path::new(&path))?;
+        std::thread::sleep(interval);
+    }
+    Ok(true)
+}
+
+/// Send SIGKILL to every process in the cgroup at `path`, and below it.
+fn kill_procs(path: &std::path::Path) -> Result<(), CgroupError> {
+    // cgroups of exited processes may be removed meanwhile
+    fn read_procs(path: &std::path::Path) -> std::io::Result<Vec<libc::pid_t>> {
+        match std::fs::read_to_string(path.join("cgroup.procs")) {
+            Ok(procs) => Ok(procs.lines().filter_map(|p| p.trim().parse().ok()).collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(CgroupError::IOError(e)),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            kill_procs(&entry.path())?;
+        }
+    }
+
+    // PIDs may be reused once read, a process whose pidfd is opened is the
+    // one listed if it is still listed afterwards
+    let pidfds: Vec<PidFd> = read_procs(path)?.into_iter().filter_map(|pid| PidFd::open(pid).ok()).collect();
+    let procs = read_procs(path)?;
+    for pidfd in pidfds.iter().filter(|p| procs.contains(&p.pid())) {
+        // it may have exited already
+        let _ = pidfd.send_signal(libc::SIGKILL);
+    }
+    Ok(())
+}
+
+/// Remove the cgroup of the bottle, and the ones left inside by systemd.
+pub fn remove_bottle_cgroup(config: &Config) -> Result<(), CgroupError> {
+    fn remove(path: &std::path::Path) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                remove(&entry.path())?;
+            }
+        }
+        std::fs::remove_dir(path)
+    }
+
+    if let Some(path) = get_bottle_cgroup(config) {
+        // exited processes may still be waiting to be reaped
+        wait_unpopulated(&path, std::time::Duration::from_secs(1))?;
+        log::trace!("removing cgroup {}", path);
+        remove(std::path::Path::new(&path))?;
+    }
+    Ok(())
+}
+
+/// Unmount the cgroup2 mounted under the runtime directory, if any, once the
+/// cgroup of the bottle is removed.
+pub fn release_cgroup2_mount(config: &Config) -> Result<(), CgroupError> {
+    let path = get_fallback_mount(config);
+    let mounts = find_cgroup2_mounts(&std::fs::read_to_string("/proc/self/mountinfo")?);
+    if mounts.contains(&path) {
+        log::trace!("unmounting {}", path);
+        nix::mount::umount(path.as_str())?;
+    }
+    match std::fs::remove_dir(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_cgroup2_mounts() {
+        let mountinfo = "\
+25 30 0:23 / /sys/fs/cgroup rw,nosuid - tmpfs tmpfs ro,mode=755
+33 25 0:28 / /sys/fs/cgroup/unified rw,nosuid - cgroup2 cgroup2 rw
+40 25 0:28 /bottled-shell /mnt/x rw - cgroup2 cgroup2 rw
+41 25 0:30 / /sys/fs/cgroup/memory rw - cgroup cgroup rw,memory
+42 30 0:28 / /run/bottled\\040shell/cgroup rw - cgroup2 cgroup2 rw
+";
+        assert_eq!(
+            find_cgroup2_mounts(mountinfo),
+            vec!["/sys/fs/cgroup/unified".to_string(), "/run/bottled shell/cgroup".to_string()]
+        );
+        assert!(find_cgroup2_mounts("").is_empty());
+    }
+
+    #[test]
+    fn test_kill_procs() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("init.scope");
+        std::fs::create_dir(&nested).unwrap();
+        let mut top = std::process::Command::new("sleep").arg("60").spawn().unwrap();
+        let mut below = std::process::Command::new("sleep").arg("60").spawn().unwrap();
+        std::fs::write(dir.path().join("cgroup.procs"), format!("{}\n", top.id())).unwrap();
+        std::fs::write(nested.join("cgroup.procs"), format!("{}\n999999999\n", below.id())).unwrap();
+
+        kill_procs(dir.path()).unwrap();
+        for child in [&mut top, &mut below] {
+            let status = child.wait().unwrap();
+            assert_eq!(std::os::unix::process::ExitStatusExt::signal(&status), Some(libc::SIGKILL));
+        }
+
+        // removed meanwhile
+        kill_procs(&dir.path().join("user.slice")).unwrap();
+    }
+}
@@ -0,0 +1,514 @@
+//! Settings of bottled, read from `/etc/bottled-shell/config.toml`.
+//!
+//! bottled runs with setuid, so the file must be owned by root and not
+//! writable by others, and unknown keys are rejected rather than ignored.
+
+use serde::Deserialize;
+
+pub static CONFIG_FILE: &str = "/etc/bottled-shell/config.toml";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("{0} must be owned by root and not writable by group or others")]
+    InsecurePermission(String),
+
+    #[error("failed to parse {0}: {1}")]
+    ParseError(String, toml::de::Error),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Settings of bottled, loaded from `/etc/bottled-shell/config.toml`.
+///
+/// Every key is optional, a missing key (or a missing file) takes the
+/// default value documented on the field.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bottle: BottleConfig,
+    pub systemd: SystemdConfig,
+    pub environment: EnvironmentConfig,
+    pub shell: ShellConfig,
+    pub cgroup: CgroupConfig,
+    pub interop: InteropConfig,
+    pub units: UnitsConfig,
+    pub guard: GuardConfig,
+    pub wslg: WslgConfig,
+    /// Mounts applied in order inside the bottle before systemd is started.
+    #[serde(rename = "mount")]
+    pub mounts: Vec<MountConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BottleConfig {
+    /// Directory holding the runtime state, default `/run/bottled-shell`.
+    pub run_dir: String,
+
+    /// PID file of bottled systemd, default `/run/bottled-shell/systemd.pid`.
+    pub pid_file: String,
+
+    /// Namespaces created in addition to the PID and mount namespaces,
+    /// default none.
+    pub namespaces: Vec<Namespace>,
+
+    /// Hostname of the bottle, `%h` is replaced with the hostname of WSL,
+    /// default `%h-wsl`. Only applied with the `uts` namespace, an empty
+    /// string keeps the hostname of WSL.
+    pub hostname: String,
+}
+
+impl Default for BottleConfig {
+    fn default() -> Self {
+        BottleConfig {
+            run_dir: "/run/bottled-shell".to_string(),
+            pid_file: "/run/bottled-shell/systemd.pid".to_string(),
+            namespaces: Vec::new(),
+            hostname: "%h-wsl".to_string(),
+        }
+    }
+}
+
+/// Optional namespaces of the bottle.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Namespace {
+    /// Hostname and NIS domain name.
+    Uts,
+    /// System V IPC and POSIX message queues.
+    Ipc,
+    /// cgroup root directory.
+    Cgroup,
+}
+
+impl Namespace {
+    /// Name of the namespace under `/proc/<pid>/ns`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Namespace::Uts => "uts",
+            Namespace::Ipc => "ipc",
+            Namespace::Cgroup => "cgroup",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SystemdConfig {
+    /// Locations searched for systemd, the first existing one is used.
+    pub search_path: Vec<String>,
+
+    /// Locations searched for machinectl, the first existing one is used.
+    pub machinectl_search_path: Vec<String>,
+
+    /// System bus socket, as seen inside the bottle. systemd is reached
+    /// through its private socket when the bus is not available.
+    pub bus_socket: String,
+
+    /// Seconds to wait for systemd to be started, default 10.
+    pub start_timeout: u64,
+
+    /// Seconds to wait for systemd to finish booting, default 30.
+    pub boot_timeout: u64,
+
+    /// Units required to be active before the bottle is considered booted.
+    /// When empty, wait until systemd reports the boot finished.
+    pub wait_units: Vec<String>,
+
+    /// Seconds to wait for systemd to power off, before SIGTERM, default 30.
+    pub stop_timeout: u64,
+
+    /// Seconds to wait after SIGTERM, and after SIGKILL, default 5.
+    pub kill_timeout: u64,
+
+    /// Whether `bottled generator` is installed as a generator of systemd in
+    /// the bottle, default true.
+    pub generator: bool,
+}
+
+impl Default for SystemdConfig {
+    fn default() -> Self {
+        SystemdConfig {
+            search_path: vec![
+                "/lib/systemd/systemd".to_string(),
+                "/usr/lib/systemd/systemd".to_string(),
+            ],
+            machinectl_search_path: vec![
+                "/usr/bin/machinectl".to_string(),
+                "/bin/machinectl".to_string(),
+            ],
+            bus_socket: "/run/dbus/system_bus_socket".to_string(),
+            start_timeout: 10,
+            boot_timeout: 30,
+            wait_units: Vec::new(),
+            stop_timeout: 30,
+            kill_timeout: 5,
+            generator: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct EnvironmentConfig {
+    /// Environment variables passed into the bottle, in addition to the
+    /// ones matching `WT_*` and `*WSL*`.
+    pub preserve: Vec<String>,
+}
+
+impl Default for EnvironmentConfig {
+    fn default() -> Self {
+        EnvironmentConfig {
+            preserve: [
+                "WSLENV",
+                "WSL_INTEROP",
+                "WSL_DISTRO_NAME",
+                "WSL_NAME",
+                "WT_SESSION",
+                "WT_PROFILE_ID",
+                "PULSE_SERVER",
+                "WAYLAND_DISPLAY",
+                "DISPLAY",
+                "BOTTLED_SHELL_LOG",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        }
+    }
+}
+
+/// How `bottled shell` enters the bottle.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionType {
+    /// machinectl when available, PAM otherwise.
+    Auto,
+    /// `machinectl shell`, requires systemd-container.
+    Machinectl,
+    /// Join the namespaces and open a PAM session directly.
+    Pam,
+}
+
+impl std::str::FromStr for SessionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(SessionType::Auto),
+            "machinectl" => Ok(SessionType::Machinectl),
+            "pam" => Ok(SessionType::Pam),
+            _ => Err(format!("unknown session type '{}'", s)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ShellConfig {
+    /// How to enter the bottle, `auto`, `machinectl` or `pam`, default `auto`.
+    pub session: SessionType,
+
+    /// PAM service used by `pam` sessions, default `login`.
+    pub pam_service: String,
+}
+
+impl Default for ShellConfig {
+    fn default() -> Self {
+        ShellConfig {
+            session: SessionType::Auto,
+            pam_service: "login".to_string(),
+        }
+    }
+}
+
+/// Value written to a cgroup interface file, e.g. `100` or `"4G"`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum CgroupValue {
+    Integer(i64),
+    String(String),
+}
+
+impl std::fmt::Display for CgroupValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CgroupValue::Integer(v) => write!(f, "{}", v),
+            CgroupValue::String(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CgroupConfig {
+    /// cgroup of the bottle, relative to the root of the cgroup2 hierarchy,
+    /// default `bottled-shell`.
+    pub path: String,
+
+    /// Interface files written to the cgroup of the bottle before systemd is
+    /// started, e.g. `memory.max`, `cpu.weight` or `pids.max`, default none.
+    pub limits: std::collections::BTreeMap<String, CgroupValue>,
+}
+
+impl Default for CgroupConfig {
+    fn default() -> Self {
+        CgroupConfig {
+            path: "bottled-shell".to_string(),
+            limits: std::collections::BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct InteropConfig {
+    /// Whether the binfmt_misc entry of the Windows interop is recorded
+    /// before systemd is started, and registered again once it booted,
+    /// default true.
+    pub restore: bool,
+
+    /// Name of the binfmt_misc entry, default `WSLInterop`.
+    pub binfmt_name: String,
+
+    /// Mount point of binfmt_misc, default `/proc/sys/fs/binfmt_misc`.
+    pub binfmt_misc: String,
+}
+
+impl Default for InteropConfig {
+    fn default() -> Self {
+        InteropConfig {
+            restore: true,
+            binfmt_name: "WSLInterop".to_string(),
+            binfmt_misc: "/proc/sys/fs/binfmt_misc".to_string(),
+        }
+    }
+}
+
+/// Runtime masks of units, written under `/run/systemd/system`.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UnitsConfig {
+    /// Units masked in addition to the built-in ones, default none.
+    pub mask: Vec<String>,
+
+    /// Built-in masks not applied, e.g. `getty@tty1.service`, default none.
+    /// `systemd-resolved.service` is only masked when `bottled generator` is
+    /// not installed.
+    pub unmask: Vec<String>,
+}
+
+/// Files generated by WSL, mounted read-only inside the bottle.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GuardConfig {
+    /// Whether the files are guarded, default false.
+    pub enable: bool,
+
+    /// Files guarded, default `/etc/resolv.conf` and `/etc/hosts`.
+    pub files: Vec<String>,
+}
+
+impl Default for GuardConfig {
+    fn default() -> Self {
+        GuardConfig {
+            enable: false,
+            files: vec!["/etc/resolv.conf".to_string(), "/etc/hosts".to_string()],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WslgConfig {
+    /// Whether the sockets of WSLg are linked into the runtime directory of
+    /// each user, and `/tmp/.X11-unix` is bound inside the bottle, default
+    /// true.
+    pub enable: bool,
+
+    /// Mount point of WSLg, default `/mnt/wslg`.
+    pub path: String,
+}
+
+impl Default for WslgConfig {
+    fn default() -> Self {
+        WslgConfig {
+            enable: true,
+            path: "/mnt/wslg".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MountType {
+    /// Bind mount `source` to `target`.
+    Bind,
+    /// Mount a tmpfs at `target`, with `options` as mount data.
+    Tmpfs,
+    /// Only change the propagation of `target`.
+    Propagation,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Propagation {
+    Shared,
+    Slave,
+    Private,
+    Unbindable,
+}
+
+/// An entry of `[[mount]]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MountConfig {
+    #[serde(rename = "type")]
+    pub kind: MountType,
+
+    /// Source of `bind` mounts.
+    #[serde(default)]
+    pub source: Option<String>,
+
+    pub target: String,
+
+    /// Mount data of `tmpfs` mounts, e.g. `size=1G,mode=1777`.
+    #[serde(default)]
+    pub options: Option<String>,
+
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// Whether bind mounts and propagation changes apply to submounts,
+    /// default true.
+    #[serde(default = "default_recursive")]
+    pub recursive: bool,
+
+    /// Propagation set on `target` after mounting, among the mounts of the
+    /// bottle, required by `propagation` entries. Mounts never propagate to
+    /// WSL whatever it is, `/` is a slave of WSL by then.
+    #[serde(default)]
+    pub propagation: Option<Propagation>,
+}
+
+fn default_recursive() -> bool {
+    true
+}
+
+impl std::fmt::Display for MountConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            MountType::Bind => match &self.source {
+                Some(source) => write!(f, "bind {} on {}", source, self.target),
+                None => write!(f, "bind on {}", self.target),
+            },
+            MountType::Tmpfs => write!(f, "tmpfs on {}", self.target),
+            MountType::Propagation => write!(f, "propagation of {}", self.target),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::load_from(CONFIG_FILE)
+    }
+
+    pub fn load_from(path: &str) -> Result<Config, ConfigError> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = match std::fs::metadata(path) {
+            Ok(m) => m,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::trace!("check {}: missing, using defaults", path);
+                return Ok(Config::default());
+            }
+            Err(e) => return Err(ConfigError::IOError(e)),
+        };
+
+        // bottled runs with setuid, a config file writable by normal users
+        // would be a privilege escalation
+        if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+            log::error!("refusing to load {}", path);
+            return Err(ConfigError::InsecurePermission(path.to_string()));
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        let config = Config::parse(&content)
+            .map_err(|e| ConfigError::ParseError(path.to_string(), e))?;
+        log::trace!("loaded {}: {:?}", path, config);
+        Ok(config)
+    }
+
+    pub fn parse(content: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default() {
+        // the values hard-coded before the config file
+        let config = Config::default();
+        assert_eq!(config.bottle.run_dir, "/run/bottled-shell");
+        assert_eq!(config.bottle.pid_file, "/run/bottled-shell/systemd.pid");
+        assert_eq!(config.systemd.search_path, ["/lib/systemd/systemd", "/usr/lib/systemd/systemd"]);
+        assert_eq!(config.systemd.machinectl_search_path, ["/usr/bin/machinectl", "/bin/machinectl"]);
+        assert_eq!(config.systemd.start_timeout, 10);
+        for name in ["WSLENV", "WSL_INTEROP", "WSL_DISTRO_NAME", "WSL_NAME", "WT_SESSION", "WT_PROFILE_ID", "PULSE_SERVER", "WAYLAND_DISPLAY", "BOTTLED_SHELL_LOG"] {
+            assert!(config.environment.preserve.iter().any(|e| e == name), "{name} not preserved");
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse("[bottle]\nrun-dir = \"/run/test\"\n").unwrap();
+        assert_eq!(config.bottle.run_dir, "/run/test");
+        assert_eq!(config.bottle.pid_file, "/run/bottled-shell/systemd.pid");
+        assert_eq!(config.systemd.start_timeout, 10);
+        assert!(Config::parse("").is_ok());
+    }
+
+    #[test]
+    fn test_parse_unknown_fields() {
+        assert!(Config::parse("[bottle]\nrun_dir = \"/run/test\"\n").is_err());
+        assert!(Config::parse("[unknown]\n").is_err());
+        assert!(Config::parse("unknown = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_readme() {
+        let readme = include_str!("../README.md");
+        let (_, example) = readme.split_once("```toml\n").unwrap();
+        let (example, _) = example.split_once("```").unwrap();
+        let config = Config::parse(example).unwrap();
+        assert_eq!(config.bottle.pid_file, Config::default().bottle.pid_file);
+        assert_eq!(config.environment.preserve, Config::default().environment.preserve);
+    }
+
+    #[test]
+    fn test_load_from() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let path = path.to_str().unwrap();
+        assert_eq!(Config::load_from(path).unwrap().bottle.run_dir, "/run/bottled-shell");
+
+        std::fs::write(path, "[bottle]\nrun-dir = \"/run/test\"\n").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o664)).unwrap();
+        assert!(matches!(Config::load_from(path), Err(ConfigError::InsecurePermission(_))));
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        if nix::unistd::getuid().is_root() {
+            assert_eq!(Config::load_from(path).unwrap().bottle.run_dir, "/run/test");
+            std::fs::write(path, "[bottle]\nrun-dir = 1\n").unwrap();
+            assert!(matches!(Config::load_from(path), Err(ConfigError::ParseError(..))));
+            nix::unistd::chown(path, Some(nix::unistd::Uid::from_raw(1000)), None).unwrap();
+        }
+        // owned by a normal user
+        assert!(matches!(Config::load_from(path), Err(ConfigError::InsecurePermission(_))));
+    }
+}
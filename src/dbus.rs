@@ -0,0 +1,789 @@
+//! Minimal D-Bus client, enough to call methods of systemd.
+//!
+//! Only the parts of the specification needed by bottled are implemented:
+//! EXTERNAL authentication over a UNIX socket, method calls, and the basic
+//! and container types (no UNIX file descriptor passing).
+
+use std::io::{BufRead, Read, Write};
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Maximum length of a message, from the specification.
+const MAX_MESSAGE_LENGTH: usize = 128 * 1024 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DBusError {
+    #[error("D-Bus authentication rejected: {0}")]
+    AuthenticationFailed(String),
+
+    #[error("malformed D-Bus message: {0}")]
+    MalformedMessage(String),
+
+    #[error("{0}: {1}")]
+    MethodError(String, String),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Byte(u8),
+    Boolean(bool),
+    Int16(i16),
+    UInt16(u16),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Double(f64),
+    String(String),
+    ObjectPath(String),
+    Signature(String),
+    /// Signature of the elements, and the elements.
+    Array(String, Vec<Value>),
+    Struct(Vec<Value>),
+    DictEntry(Box<Value>, Box<Value>),
+    Variant(Box<Value>),
+}
+
+impl Value {
+    pub fn signature(&self) -> String {
+        match self {
+            Value::Byte(_) => "y".to_string(),
+            Value::Boolean(_) => "b".to_string(),
+            Value::Int16(_) => "n".to_string(),
+            Value::UInt16(_) => "q".to_string(),
+            Value::Int32(_) => "i".to_string(),
+            Value::UInt32(_) => "u".to_string(),
+            Value::Int64(_) => "x".to_string(),
+            Value::UInt64(_) => "t".to_string(),
+            Value::Double(_) => "d".to_string(),
+            Value::String(_) => "s".to_string(),
+            Value::ObjectPath(_) => "o".to_string(),
+            Value::Signature(_) => "g".to_string(),
+            Value::Array(s, _) => format!("a{}", s),
+            Value::Struct(v) => format!("({})", v.iter().map(|e| e.signature()).collect::<String>()),
+            Value::DictEntry(k, v) => format!("{{{}{}}}", k.signature(), v.signature()),
+            Value::Variant(_) => "v".to_string(),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) | Value::ObjectPath(s) | Value::Signature(s) => Some(s),
+            Value::Variant(v) => v.as_str(),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Value::UInt32(v) => Some(*v),
+            Value::Variant(v) => v.as_u32(),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::UInt64(v) => Some(*v),
+            Value::Variant(v) => v.as_u64(),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(_, v) => Some(v),
+            Value::Variant(v) => v.as_array(),
+            _ => None,
+        }
+    }
+
+    pub fn as_struct(&self) -> Option<&[Value]> {
+        match self {
+            Value::Struct(v) => Some(v),
+            Value::Variant(v) => v.as_struct(),
+            _ => None,
+        }
+    }
+}
+
+fn alignment(signature: &str) -> usize {
+    match signature.as_bytes().first() {
+        Some(b'y') | Some(b'g') | Some(b'v') => 1,
+        Some(b'n') | Some(b'q') => 2,
+        Some(b'b') | Some(b'i') | Some(b'u') | Some(b's') | Some(b'o') | Some(b'a') | Some(b'h') => 4,
+        _ => 8,
+    }
+}
+
+/// Split the first complete type from a signature.
+fn split_signature(signature: &str) -> Result<(&str, &str), DBusError> {
+    let bytes = signature.as_bytes();
+    let end = match bytes.first() {
+        None => return Err(DBusError::MalformedMessage("empty signature".to_string())),
+        Some(b'a') => 1 + split_signature(&signature[1..])?.0.len(),
+        Some(open @ b'(') | Some(open @ b'{') => {
+            let close = if *open == b'(' { b')' } else { b'}' };
+            let mut depth = 0;
+            let mut end = None;
+            for (i, c) in bytes.iter().enumerate() {
+                if *c == *open {
+                    depth += 1;
+                } else if *c == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = Some(i + 1);
+                        break;
+                    }
+                }
+            }
+            end.ok_or_else(|| DBusError::MalformedMessage(format!("unbalanced signature '{}'", signature)))?
+        }
+        Some(_) => 1,
+    };
+    Ok(signature.split_at(end))
+}
+
+struct Writer {
+    buffer: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer { buffer: Vec::new() }
+    }
+
+    fn pad(&mut self, align: usize) {
+        while !self.buffer.len().is_multiple_of(align) {
+            self.buffer.push(0);
+        }
+    }
+
+    fn write_u32(&mut self, v: u32) {
+        self.pad(4);
+        self.buffer.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn write_value(&mut self, value: &Value) {
+        match value {
+            Value::Byte(v) => self.buffer.push(*v),
+            Value::Boolean(v) => self.write_u32(*v as u32),
+            Value::Int16(v) => {
+                self.pad(2);
+                self.buffer.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::UInt16(v) => {
+                self.pad(2);
+                self.buffer.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Int32(v) => {
+                self.pad(4);
+                self.buffer.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::UInt32(v) => self.write_u32(*v),
+            Value::Int64(v) => {
+                self.pad(8);
+                self.buffer.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::UInt64(v) => {
+                self.pad(8);
+                self.buffer.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Double(v) => {
+                self.pad(8);
+                self.buffer.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::String(s) | Value::ObjectPath(s) => {
+                self.write_u32(s.len() as u32);
+                self.buffer.extend_from_slice(s.as_bytes());
+                self.buffer.push(0);
+            }
+            Value::Signature(s) => {
+                self.buffer.push(s.len() as u8);
+                self.buffer.extend_from_slice(s.as_bytes());
+                self.buffer.push(0);
+            }
+            Value::Array(signature, elements) => {
+                self.write_u32(0);
+                let length_offset = self.buffer.len() - 4;
+                self.pad(alignment(signature));
+                let start = self.buffer.len();
+                for e in elements {
+                    self.write_value(e);
+                }
+                let length = (self.buffer.len() - start) as u32;
+                self.buffer[length_offset..length_offset + 4].copy_from_slice(&length.to_le_bytes());
+            }
+            Value::Struct(fields) => {
+                self.pad(8);
+                for f in fields {
+                    self.write_value(f);
+                }
+            }
+            Value::DictEntry(k, v) => {
+                self.pad(8);
+                self.write_value(k);
+                self.write_value(v);
+            }
+            Value::Variant(v) => {
+                self.write_value(&Value::Signature(v.signature()));
+                self.write_value(v);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+    position: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buffer: &'a [u8], big_endian: bool) -> Reader<'a> {
+        Reader { buffer, position: 0, big_endian }
+    }
+
+    fn align(&mut self, align: usize) {
+        while !self.position.is_multiple_of(align) {
+            self.position += 1;
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], DBusError> {
+        if self.position + length > self.buffer.len() {
+            return Err(DBusError::MalformedMessage("truncated message".to_string()));
+        }
+        let bytes = &self.buffer[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn read_fixed<const N: usize>(&mut self) -> Result<[u8; N], DBusError> {
+        self.align(N);
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(self.take(N)?);
+        if self.big_endian {
+            bytes.reverse();
+        }
+        Ok(bytes)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DBusError> {
+        Ok(u32::from_le_bytes(self.read_fixed()?))
+    }
+
+    fn read_string(&mut self, length: usize) -> Result<String, DBusError> {
+        let bytes = self.take(length + 1)?;
+        String::from_utf8(bytes[..length].to_vec())
+            .map_err(|e| DBusError::MalformedMessage(e.to_string()))
+    }
+
+    fn read_value(&mut self, signature: &str) -> Result<Value, DBusError> {
+        let value = match signature.as_bytes()[0] {
+            b'y' => Value::Byte(self.take(1)?[0]),
+            b'b' => Value::Boolean(self.read_u32()? != 0),
+            b'n' => Value::Int16(i16::from_le_bytes(self.read_fixed()?)),
+            b'q' => Value::UInt16(u16::from_le_bytes(self.read_fixed()?)),
+            b'i' => Value::Int32(i32::from_le_bytes(self.read_fixed()?)),
+            b'u' | b'h' => Value::UInt32(self.read_u32()?),
+            b'x' => Value::Int64(i64::from_le_bytes(self.read_fixed()?)),
+            b't' => Value::UInt64(u64::from_le_bytes(self.read_fixed()?)),
+            b'd' => Value::Double(f64::from_le_bytes(self.read_fixed()?)),
+            b's' => {
+                let length = self.read_u32()? as usize;
+                Value::String(self.read_string(length)?)
+            }
+            b'o' => {
+                let length = self.read_u32()? as usize;
+                Value::ObjectPath(self.read_string(length)?)
+            }
+            b'g' => {
+                let length = self.take(1)?[0] as usize;
+                Value::Signature(self.read_string(length)?)
+            }
+            b'a' => {
+                let element = &signature[1..];
+                let length = self.read_u32()? as usize;
+                self.align(alignment(element));
+                let end = self.position + length;
+                let mut elements = Vec::new();
+                while self.position < end {
+                    elements.push(self.read_value(element)?);
+                }
+                Value::Array(element.to_string(), elements)
+            }
+            b'(' => {
+                self.align(8);
+                let mut fields = Vec::new();
+                let mut rest = &signature[1..signature.len() - 1];
+                while !rest.is_empty() {
+                    let (s, r) = split_signature(rest)?;
+                    fields.push(self.read_value(s)?);
+                    rest = r;
+                }
+                Value::Struct(fields)
+            }
+            b'{' => {
+                self.align(8);
+                let (k, v) = split_signature(&signature[1..signature.len() - 1])?;
+                let key = self.read_value(k)?;
+                let value = self.read_value(v)?;
+                Value::DictEntry(Box::new(key), Box::new(value))
+            }
+            b'v' => {
+                let length = self.take(1)?[0] as usize;
+                let s = self.read_string(length)?;
+                let (s, _) = split_signature(&s)?;
+                Value::Variant(Box::new(self.read_value(s)?))
+            }
+            c => return Err(DBusError::MalformedMessage(format!("unsupported type '{}'", c as char))),
+        };
+        Ok(value)
+    }
+
+    fn read_values(&mut self, signature: &str) -> Result<Vec<Value>, DBusError> {
+        let mut values = Vec::new();
+        let mut rest = signature;
+        while !rest.is_empty() {
+            let (s, r) = split_signature(rest)?;
+            values.push(self.read_value(s)?);
+            rest = r;
+        }
+        Ok(values)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Message {
+    message_type: u8,
+    reply_serial: Option<u32>,
+    error_name: Option<String>,
+    body: Vec<Value>,
+}
+
+pub struct Connection {
+    stream: std::io::BufReader<std::os::unix::net::UnixStream>,
+    serial: u32,
+}
+
+impl Connection {
+    /// Connect to a message bus, e.g. the system bus.
+    pub fn connect_bus(path: &str) -> Result<Connection, DBusError> {
+        let mut connection = Connection::connect_peer(path)?;
+        connection.call(
+            Some("org.freedesktop.DBus"),
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+            &[],
+        )?;
+        Ok(connection)
+    }
+
+    /// Connect to a peer directly, without a message bus.
+    pub fn connect_peer(path: &str) -> Result<Connection, DBusError> {
+        log::trace!("connecting D-Bus socket {}", path);
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(25)))?;
+        let mut connection = Connection {
+            stream: std::io::BufReader::new(stream),
+            serial: 0,
+        };
+        connection.authenticate()?;
+        Ok(connection)
+    }
+
+    /// Wrap a stream already authenticated by `connect_peer`, e.g. passed
+    /// from another process.
+    pub fn from_authenticated(stream: std::os::unix::net::UnixStream) -> Connection {
+        Connection {
+            stream: std::io::BufReader::new(stream),
+            serial: 0,
+        }
+    }
+
+    /// The underlying stream, nothing is buffered between messages.
+    pub fn as_stream(&self) -> &std::os::unix::net::UnixStream {
+        self.stream.get_ref()
+    }
+
+    fn authenticate(&mut self) -> Result<(), DBusError> {
+        let uid = nix::unistd::geteuid().to_string();
+        let hex_uid: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+        let stream = self.stream.get_mut();
+        stream.write_all(b"\0")?;
+        stream.write_all(format!("AUTH EXTERNAL {}\r\n", hex_uid).as_bytes())?;
+
+        let mut line = String::new();
+        self.stream.read_line(&mut line)?;
+        if !line.starts_with("OK ") {
+            return Err(DBusError::AuthenticationFailed(line.trim().to_string()));
+        }
+        self.stream.get_mut().write_all(b"BEGIN\r\n")?;
+        log::trace!("D-Bus authenticated as UID={}", uid);
+        Ok(())
+    }
+
+    /// Call a method, and wait for its reply.
+    pub fn call(
+        &mut self,
+        destination: Option<&str>,
+        path: &str,
+        interface: &str,
+        member: &str,
+        args: &[Value],
+    ) -> Result<Vec<Value>, DBusError> {
+        self.serial += 1;
+        let serial = self.serial;
+
+        let mut body = Writer::new();
+        for a in args {
+            body.write_value(a);
+        }
+        let signature: String = args.iter().map(|a| a.signature()).collect();
+
+        let field = |code: u8, value: Value| {
+            Value::Struct(vec![Value::Byte(code), Value::Variant(Box::new(value))])
+        };
+        let mut fields = vec![
+            field(FIELD_PATH, Value::ObjectPath(path.to_string())),
+            field(FIELD_INTERFACE, Value::String(interface.to_string())),
+            field(FIELD_MEMBER, Value::String(member.to_string())),
+        ];
+        if let Some(d) = destination {
+            fields.push(field(FIELD_DESTINATION, Value::String(d.to_string())));
+        }
+        if !signature.is_empty() {
+            fields.push(field(FIELD_SIGNATURE, Value::Signature(signature)));
+        }
+
+        let mut message = Writer::new();
+        message.buffer.extend_from_slice(&[b'l', METHOD_CALL, 0, 1]);
+        message.write_u32(body.buffer.len() as u32);
+        message.write_u32(serial);
+        message.write_value(&Value::Array("(yv)".to_string(), fields));
+        message.pad(8);
+        message.buffer.extend_from_slice(&body.buffer);
+
+        log::trace!("D-Bus call #{}: {}.{} on {}", serial, interface, member, path);
+        self.stream.get_mut().write_all(&message.buffer)?;
+
+        loop {
+            let reply = self.receive()?;
+            if reply.reply_serial != Some(serial) {
+                continue;
+            }
+            match reply.message_type {
+                METHOD_RETURN => return Ok(reply.body),
+                ERROR => {
+                    let name = reply.error_name.unwrap_or_default();
+                    let text = reply.body.first().and_then(|v| v.as_str()).unwrap_or("").to_string();
+                    log::trace!("D-Bus call #{} failed: {}: {}", serial, name, text);
+                    return Err(DBusError::MethodError(name, text));
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Result<Message, DBusError> {
+        let mut fixed = [0u8; 16];
+        self.stream.read_exact(&mut fixed)?;
+        let big_endian = match fixed[0] {
+            b'l' => false,
+            b'B' => true,
+            c => return Err(DBusError::MalformedMessage(format!("unknown endianness '{}'", c as char))),
+        };
+        let mut reader = Reader::new(&fixed, big_endian);
+        reader.position = 4;
+        let body_length = reader.read_u32()? as usize;
+        let _serial = reader.read_u32()?;
+        let fields_length = reader.read_u32()? as usize;
+
+        let header_length = (16 + fields_length).div_ceil(8) * 8;
+        if header_length + body_length > MAX_MESSAGE_LENGTH {
+            return Err(DBusError::MalformedMessage(format!(
+                "message of {} bytes exceeds the maximum length", header_length + body_length
+            )));
+        }
+        let mut buffer = fixed.to_vec();
+        buffer.resize(header_length + body_length, 0);
+        self.stream.read_exact(&mut buffer[16..])?;
+
+        let mut message = Message { message_type: fixed[1], ..Default::default() };
+        let mut signature = String::new();
+        let mut reader = Reader::new(&buffer[..header_length], big_endian);
+        reader.position = 12;
+        if let Value::Array(_, fields) = reader.read_value("a(yv)")? {
+            for f in fields {
+                if let Value::Struct(f) = f {
+                    match (&f[0], &f[1]) {
+                        (Value::Byte(FIELD_REPLY_SERIAL), v) => message.reply_serial = v.as_u32(),
+                        (Value::Byte(FIELD_ERROR_NAME), v) => message.error_name = v.as_str().map(|s| s.to_string()),
+                        (Value::Byte(FIELD_SIGNATURE), v) => signature = v.as_str().unwrap_or("").to_string(),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let mut reader = Reader::new(&buffer[header_length..], big_endian);
+        message.body = reader.read_values(&signature)?;
+        Ok(message)
+    }
+
+    pub fn get_property(&mut self, destination: Option<&str>, path: &str, interface: &str, property: &str) -> Result<Value, DBusError> {
+        let reply = self.call(
+            destination,
+            path,
+            "org.freedesktop.DBus.Properties",
+            "Get",
+            &[Value::String(interface.to_string()), Value::String(property.to_string())],
+        )?;
+        reply.into_iter().next()
+            .ok_or_else(|| DBusError::MalformedMessage(format!("no value of property {}", property)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read a message on the daemon side, returns its serial, header fields
+    /// and body.
+    fn read_message(stream: &mut impl Read) -> (u32, Vec<(u8, Value)>, Vec<Value>) {
+        let mut fixed = [0u8; 16];
+        stream.read_exact(&mut fixed).unwrap();
+        let mut reader = Reader::new(&fixed, false);
+        reader.position = 4;
+        let body_length = reader.read_u32().unwrap() as usize;
+        let serial = reader.read_u32().unwrap();
+        let fields_length = reader.read_u32().unwrap() as usize;
+        let header_length = (16 + fields_length).div_ceil(8) * 8;
+        let mut buffer = fixed.to_vec();
+        buffer.resize(header_length + body_length, 0);
+        stream.read_exact(&mut buffer[16..]).unwrap();
+
+        let mut reader = Reader::new(&buffer[..header_length], false);
+        reader.position = 12;
+        let mut fields = Vec::new();
+        let mut signature = String::new();
+        for f in reader.read_value("a(yv)").unwrap().as_array().unwrap() {
+            let f = f.as_struct().unwrap();
+            let (code, value) = match (&f[0], &f[1]) {
+                (Value::Byte(c), Value::Variant(v)) => (*c, (**v).clone()),
+                _ => panic!("malformed header field {:?}", f),
+            };
+            if code == FIELD_SIGNATURE {
+                signature = value.as_str().unwrap().to_string();
+            }
+            fields.push((code, value));
+        }
+        let body = Reader::new(&buffer[header_length..], false).read_values(&signature).unwrap();
+        (serial, fields, body)
+    }
+
+    fn write_reply(stream: &mut impl Write, message_type: u8, serial: u32, fields: Vec<(u8, Value)>, body: &[Value]) {
+        let mut writer = Writer::new();
+        for v in body {
+            writer.write_value(v);
+        }
+        let mut fields: Vec<Value> = fields
+            .into_iter()
+            .map(|(c, v)| Value::Struct(vec![Value::Byte(c), Value::Variant(Box::new(v))]))
+            .collect();
+        fields.push(Value::Struct(vec![Value::Byte(FIELD_REPLY_SERIAL), Value::Variant(Box::new(Value::UInt32(serial)))]));
+        let signature: String = body.iter().map(|v| v.signature()).collect();
+        if !signature.is_empty() {
+            fields.push(Value::Struct(vec![Value::Byte(FIELD_SIGNATURE), Value::Variant(Box::new(Value::Signature(signature)))]));
+        }
+
+        let mut message = Writer::new();
+        message.buffer.extend_from_slice(&[b'l', message_type, 0, 1]);
+        message.write_u32(writer.buffer.len() as u32);
+        message.write_u32(1000 + serial);
+        message.write_value(&Value::Array("(yv)".to_string(), fields));
+        message.pad(8);
+        message.buffer.extend_from_slice(&writer.buffer);
+        stream.write_all(&message.buffer).unwrap();
+    }
+
+    /// Stand-in of a D-Bus peer at a socket under a temporary directory,
+    /// accepting one connection and handing it to `serve` once authenticated.
+    fn stand_in<F>(serve: F) -> (tempfile::TempDir, String, std::thread::JoinHandle<()>)
+    where
+        F: FnOnce(&mut std::io::BufReader<std::os::unix::net::UnixStream>) + Send + 'static,
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bus").to_string_lossy().to_string();
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = std::io::BufReader::new(stream);
+            let mut nul = [0u8; 1];
+            stream.read_exact(&mut nul).unwrap();
+            assert_eq!(nul[0], 0);
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            assert!(line.starts_with("AUTH EXTERNAL "), "{}", line);
+            stream.get_mut().write_all(b"OK 0123456789abcdef\r\n").unwrap();
+            line.clear();
+            stream.read_line(&mut line).unwrap();
+            assert_eq!(line, "BEGIN\r\n");
+            serve(&mut stream);
+        });
+        (dir, path, handle)
+    }
+
+    #[test]
+    fn test_split_signature() {
+        assert_eq!(split_signature("sa{sv}").unwrap(), ("s", "a{sv}"));
+        assert_eq!(split_signature("a(sa(sv))u").unwrap(), ("a(sa(sv))", "u"));
+        assert_eq!(split_signature("(ss)").unwrap(), ("(ss)", ""));
+        assert!(split_signature("(ss").is_err());
+        assert!(split_signature("").is_err());
+    }
+
+    #[test]
+    fn test_value_roundtrip() {
+        let values = vec![
+            Value::Byte(7),
+            Value::Boolean(true),
+            Value::Int16(-2),
+            Value::UInt64(1 << 40),
+            Value::String("systemd".to_string()),
+            Value::ObjectPath("/org/freedesktop/systemd1".to_string()),
+            Value::Array(
+                "(sv)".to_string(),
+                vec![Value::Struct(vec![
+                    Value::String("Description".to_string()),
+                    Value::Variant(Box::new(Value::String("bottled".to_string()))),
+                ])],
+            ),
+            Value::Array(
+                "{su}".to_string(),
+                vec![Value::DictEntry(Box::new(Value::String("a".to_string())), Box::new(Value::UInt32(1)))],
+            ),
+            Value::Array("s".to_string(), Vec::new()),
+            Value::Double(0.5),
+        ];
+        let mut writer = Writer::new();
+        for v in &values {
+            writer.write_value(v);
+        }
+        let signature: String = values.iter().map(|v| v.signature()).collect();
+        assert_eq!(signature, "ybntsoa(sv)a{su}asd");
+        let read = Reader::new(&writer.buffer, false).read_values(&signature).unwrap();
+        assert_eq!(read, values);
+    }
+
+    #[test]
+    fn test_call() {
+        let (_dir, path, daemon) = stand_in(|stream| {
+            let (serial, fields, body) = read_message(stream);
+            assert!(fields.contains(&(FIELD_PATH, Value::ObjectPath("/org/freedesktop/systemd1".to_string()))));
+            assert!(fields.contains(&(FIELD_INTERFACE, Value::String("org.freedesktop.DBus.Properties".to_string()))));
+            assert!(fields.contains(&(FIELD_MEMBER, Value::String("Get".to_string()))));
+            assert!(fields.contains(&(FIELD_DESTINATION, Value::String("org.freedesktop.systemd1".to_string()))));
+            assert_eq!(body, vec![
+                Value::String("org.freedesktop.systemd1.Manager".to_string()),
+                Value::String("SystemState".to_string()),
+            ]);
+            // replies to other calls are skipped
+            write_reply(stream.get_mut(), METHOD_RETURN, serial + 1, Vec::new(), &[Value::UInt32(0)]);
+            write_reply(
+                stream.get_mut(),
+                METHOD_RETURN,
+                serial,
+                Vec::new(),
+                &[Value::Variant(Box::new(Value::String("running".to_string())))],
+            );
+        });
+
+        let mut connection = Connection::connect_peer(&path).unwrap();
+        let state = connection
+            .get_property(Some("org.freedesktop.systemd1"), "/org/freedesktop/systemd1", "org.freedesktop.systemd1.Manager", "SystemState")
+            .unwrap();
+        assert_eq!(state.as_str(), Some("running"));
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn test_call_error() {
+        let (_dir, path, daemon) = stand_in(|stream| {
+            let (serial, fields, body) = read_message(stream);
+            assert!(fields.contains(&(FIELD_MEMBER, Value::String("GetUnit".to_string()))));
+            assert!(!fields.iter().any(|(c, _)| *c == FIELD_DESTINATION));
+            assert_eq!(body, vec![Value::String("foo.service".to_string())]);
+            write_reply(
+                stream.get_mut(),
+                ERROR,
+                serial,
+                vec![(FIELD_ERROR_NAME, Value::String("org.freedesktop.systemd1.NoSuchUnit".to_string()))],
+                &[Value::String("Unit foo.service not loaded.".to_string())],
+            );
+        });
+
+        let mut connection = Connection::connect_peer(&path).unwrap();
+        let result = connection.call(
+            None,
+            "/org/freedesktop/systemd1",
+            "org.freedesktop.systemd1.Manager",
+            "GetUnit",
+            &[Value::String("foo.service".to_string())],
+        );
+        match result {
+            Err(DBusError::MethodError(name, text)) => {
+                assert_eq!(name, "org.freedesktop.systemd1.NoSuchUnit");
+                assert_eq!(text, "Unit foo.service not loaded.");
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn test_authentication_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bus").to_string_lossy().to_string();
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let daemon = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = std::io::BufReader::new(stream);
+            // the NUL byte and AUTH may arrive apart, the client fails to
+            // write AUTH when closed meanwhile
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            stream.get_mut().write_all(b"REJECTED EXTERNAL\r\n").unwrap();
+        });
+        assert!(matches!(Connection::connect_peer(&path), Err(DBusError::AuthenticationFailed(_))));
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn test_message_too_long() {
+        let (client, mut daemon) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut connection = Connection { stream: std::io::BufReader::new(client), serial: 0 };
+        let mut header = Writer::new();
+        header.buffer.extend_from_slice(&[b'l', METHOD_RETURN, 0, 1]);
+        header.write_u32(u32::MAX);
+        header.write_u32(1);
+        header.write_u32(0);
+        daemon.write_all(&header.buffer).unwrap();
+        assert!(matches!(connection.receive(), Err(DBusError::MalformedMessage(_))));
+    }
+}
@@ -0,0 +1,127 @@
+//! Checks of the things known to break inside or around the bottle.
+
+use serde::Serialize;
+
+use crate::binfmt::{self, BinfmtEntry};
+use crate::config::Config;
+use crate::guard::{self, GuardState};
+use crate::systemd;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckState {
+    Ok,
+    /// Not applicable, e.g. systemd is not running.
+    Skipped,
+    Failed,
+}
+
+impl std::fmt::Display for CheckState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CheckState::Ok => "ok",
+            CheckState::Skipped => "skipped",
+            CheckState::Failed => "failed",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub state: CheckState,
+    pub message: String,
+}
+
+impl Check {
+    fn new(name: &'static str, state: CheckState, message: String) -> Check {
+        Check { name, state, message }
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}: {}", self.state, self.name, self.message)
+    }
+}
+
+fn check_systemd(config: &Config) -> Check {
+    if systemd::is_associated_with_systemd(config) {
+        return Check::new("systemd", CheckState::Ok, "inside the bottle".to_string());
+    }
+    match systemd::get_systemd_pid(config) {
+        Ok(Some(pid)) => Check::new("systemd", CheckState::Ok, format!("running, PID={}", pid)),
+        Ok(None) if systemd::is_pid_file_stale(config).unwrap_or(false) => {
+            Check::new("systemd", CheckState::Failed, "stale PID file, systemd exited unexpectedly".to_string())
+        }
+        Ok(None) => Check::new("systemd", CheckState::Skipped, "not running".to_string()),
+        Err(e) => Check::new("systemd", CheckState::Failed, e.to_string()),
+    }
+}
+
+/// Check the binfmt_misc entry of the Windows interop.
+pub fn check_interop(config: &Config) -> Check {
+    let interop = &config.interop;
+    if std::fs::metadata(format!("{}/register", interop.binfmt_misc)).is_err() {
+        return Check::new(
+            "interop",
+            CheckState::Failed,
+            format!("binfmt_misc not mounted at {}", interop.binfmt_misc),
+        );
+    }
+    match BinfmtEntry::read(&interop.binfmt_misc, &interop.binfmt_name) {
+        Ok(Some(e)) if e.enabled => Check::new(
+            "interop",
+            CheckState::Ok,
+            format!("{} registered, interpreter {}", e.name, e.interpreter),
+        ),
+        Ok(Some(e)) => Check::new("interop", CheckState::Failed, format!("{} disabled", e.name)),
+        Ok(None) => {
+            let hint = match binfmt::read_recorded_interop(config) {
+                Ok(Some(_)) => ", `bottled reload` registers it again",
+                _ => "",
+            };
+            Check::new(
+                "interop",
+                CheckState::Failed,
+                format!("{} not registered in {}{}", interop.binfmt_name, interop.binfmt_misc, hint),
+            )
+        }
+        Err(e) => Check::new("interop", CheckState::Failed, e.to_string()),
+    }
+}
+
+/// Check the files generated by WSL are still protected inside the bottle.
+fn check_guard(config: &Config) -> Check {
+    if !config.guard.enable {
+        return Check::new("guard", CheckState::Skipped, "disabled".to_string());
+    }
+    let pid = if systemd::is_associated_with_systemd(config) {
+        1
+    } else {
+        match systemd::get_systemd_pid(config) {
+            Ok(Some(pid)) => pid,
+            _ => return Check::new("guard", CheckState::Skipped, "systemd not running".to_string()),
+        }
+    };
+    match guard::get_guard_status(config, pid) {
+        Ok(status) => {
+            let failed: Vec<String> = status
+                .iter()
+                .filter(|g| g.state != GuardState::Protected)
+                .map(|g| format!("{} {}", g.path, g.state))
+                .collect();
+            if failed.is_empty() {
+                Check::new("guard", CheckState::Ok, format!("{} files protected", status.len()))
+            } else {
+                Check::new("guard", CheckState::Failed, failed.join(", "))
+            }
+        }
+        Err(e) => Check::new("guard", CheckState::Failed, e.to_string()),
+    }
+}
+
+pub fn run_checks(config: &Config) -> Vec<Check> {
+    vec![check_systemd(config), check_interop(config), check_guard(config)]
+}
@@ -0,0 +1,198 @@
+use crate::cgroup::{self, CgroupError};
+use crate::config::Config;
+use crate::systemd;
+use crate::wslg;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ExecError {
+    #[error("user '{0}' not found")]
+    UserNotFound(String),
+
+    #[error("no enough permission to run as '{0}'")]
+    NoEnoughPermission(String),
+
+    #[error("failed to change directory to {0}: {1}")]
+    ChangeDirectory(String, nix::errno::Errno),
+
+    #[error(transparent)]
+    CgroupError(#[from] CgroupError),
+
+    #[error(transparent)]
+    SystemdError(#[from] systemd::SystemdError),
+
+    #[error(transparent)]
+    NixErrno(#[from] nix::errno::Errno),
+}
+
+static CHILD_PID: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = CHILD_PID.load(std::sync::atomic::Ordering::SeqCst);
+    if pid > 0 {
+        unsafe { libc::kill(pid, signal); }
+    }
+}
+
+pub(crate) struct Credential {
+    pub uid: nix::unistd::Uid,
+    pub gid: nix::unistd::Gid,
+    pub name: std::ffi::CString,
+    pub home: String,
+    pub shell: String,
+}
+
+pub(crate) fn get_credential(user: &str) -> Result<Credential, ExecError> {
+    use std::ffi::{CStr, CString};
+
+    let name = CString::new(user).map_err(|_| ExecError::UserNotFound(user.to_string()))?;
+    let pwent = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pwent.is_null() {
+        return Err(ExecError::UserNotFound(user.to_string()));
+    }
+    let pwent = unsafe { &*pwent };
+    Ok(Credential {
+        uid: nix::unistd::Uid::from_raw(pwent.pw_uid),
+        gid: nix::unistd::Gid::from_raw(pwent.pw_gid),
+        name,
+        home: unsafe { CStr::from_ptr(pwent.pw_dir) }.to_string_lossy().to_string(),
+        shell: unsafe { CStr::from_ptr(pwent.pw_shell) }.to_string_lossy().to_string(),
+    })
+}
+
+fn release_privilege(credential: &Option<Credential>) -> Result<(), ExecError> {
+    match credential {
+        Some(c) => {
+            log::trace!("switching to {}(UID={})", c.name.to_string_lossy(), c.uid);
+            nix::unistd::initgroups(&c.name, c.gid)?;
+            nix::unistd::setgid(c.gid)?;
+            nix::unistd::setuid(c.uid)?;
+
+            std::env::set_var("HOME", &c.home);
+            std::env::set_var("SHELL", &c.shell);
+            std::env::set_var("USER", c.name.to_string_lossy().as_ref());
+            std::env::set_var("LOGNAME", c.name.to_string_lossy().as_ref());
+        }
+        None => {
+            log::trace!("releasing privilege");
+            nix::unistd::setgid(nix::unistd::getgid())?;
+            nix::unistd::setuid(nix::unistd::getuid())?;
+        }
+    }
+    Ok(())
+}
+
+fn exec_command(credential: &Option<Credential>, cwd: &Option<String>, fallback_cwd: &Option<std::path::PathBuf>, args: &[String]) -> ! {
+    use std::ffi::CString;
+
+    if let Err(e) = release_privilege(credential) {
+        eprintln!("bottled: {}", e);
+        std::process::exit(126);
+    }
+
+    if let Some(d) = cwd {
+        if let Err(e) = nix::unistd::chdir(d.as_str()) {
+            eprintln!("bottled: {}", ExecError::ChangeDirectory(d.clone(), e));
+            std::process::exit(126);
+        }
+    } else if let Some(d) = fallback_cwd {
+        if let Err(e) = nix::unistd::chdir(d) {
+            log::warn!("failed to change directory to {}: {}", d.display(), e);
+        }
+    }
+
+    let expanded_args: Vec<CString> = args
+        .iter()
+        .map(|v| CString::new(v.as_str()).unwrap())
+        .collect();
+
+    log::trace!("executing command: {:?}", expanded_args);
+    let Err(e) = nix::unistd::execvp(&expanded_args[0], &expanded_args);
+    eprintln!("bottled: {}: {}", args[0], e);
+    if e == nix::errno::Errno::ENOENT {
+        std::process::exit(127);
+    }
+    std::process::exit(126);
+}
+
+pub(crate) fn wait_command(child: nix::unistd::Pid) -> Result<i32, ExecError> {
+    use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal};
+    use nix::sys::wait::WaitStatus;
+
+    CHILD_PID.store(libc::pid_t::from(child), std::sync::atomic::Ordering::SeqCst);
+
+    // signals from the terminal reach the whole foreground process group,
+    // the others are only sent to us and must be passed to the command
+    let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+    let forward = SigAction::new(SigHandler::Handler(forward_signal), SaFlags::SA_RESTART, SigSet::empty());
+    unsafe {
+        nix::sys::signal::sigaction(Signal::SIGINT, &ignore)?;
+        nix::sys::signal::sigaction(Signal::SIGQUIT, &ignore)?;
+        for s in [Signal::SIGTERM, Signal::SIGHUP, Signal::SIGUSR1, Signal::SIGUSR2] {
+            nix::sys::signal::sigaction(s, &forward)?;
+        }
+    }
+
+    loop {
+        match nix::sys::wait::waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => {
+                log::trace!("command(PID={}) exited with {}", child, code);
+                return Ok(code);
+            }
+            Ok(WaitStatus::Signaled(_, signal, _)) => {
+                log::trace!("command(PID={}) killed by {}", child, signal);
+                return Ok(128 + signal as i32);
+            }
+            Ok(_) => continue,
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => return Err(ExecError::NixErrno(e)),
+        }
+    }
+}
+
+/// Run a command inside the bottle, returns the exit status of the command.
+///
+/// The command is executed directly without a login session. Standard IO is
+/// inherited, and the exit status is passed through, a command killed by a
+/// signal results in `128 + signal` like shells do.
+pub fn run_command(config: &Config, user: Option<&str>, cwd: Option<&str>, args: &[String]) -> Result<i32, ExecError> {
+    use nix::unistd::ForkResult;
+
+    let credential = match user {
+        Some(u) => {
+            let c = get_credential(u)?;
+            if !nix::unistd::getuid().is_root() && c.uid != nix::unistd::getuid() {
+                log::error!("only root is allowed to run commands as other users");
+                return Err(ExecError::NoEnoughPermission(u.to_string()));
+            }
+            Some(c)
+        }
+        None => None,
+    };
+    let cwd = cwd.map(|d| d.to_string());
+    let fallback_cwd = std::env::current_dir().ok();
+
+    if systemd::is_associated_with_systemd(config) {
+        log::trace!("already associated with bottled systemd");
+        exec_command(&credential, &cwd, &fallback_cwd, args);
+    }
+
+    // the cgroup of the bottle is out of reach once its mount and cgroup
+    // namespaces are joined, the command forked below inherits it, so that
+    // its limits apply
+    let cgroup = cgroup::get_exec_cgroup(config)?;
+    log::trace!("entering cgroup {}", cgroup);
+    cgroup::enter_cgroup(&cgroup)?;
+
+    log::trace!("associating with bottled systemd");
+    systemd::associate_with_systemd(config)?;
+
+    if let Err(e) = wslg::setup_x11(config) {
+        log::warn!("failed to set up WSLg sockets: {}", e);
+    }
+
+    // joining a PID namespace only takes effect on children
+    match unsafe { nix::unistd::fork() }? {
+        ForkResult::Parent { child } => wait_command(child),
+        ForkResult::Child => exec_command(&credential, &cwd, &fallback_cwd, args),
+    }
+}
@@ -0,0 +1,496 @@
+//! systemd generator describing the WSL environment to systemd in the bottle.
+//!
+//! systemd runs `bottled generator <normal> <early> <late>` before loading
+//! units, see systemd.generator(7). It writes units keeping systemd from
+//! unmounting the mounts managed by WSL, drop-ins keeping it from breaking
+//! the interop and `/etc/resolv.conf`, or setting up the interop and WSLg
+//! for each user, and `bottled-shell-wsl.target` wanting the mounts.
+//!
+//! bottled is setuid, so only root may run the generator, and files are
+//! never written through symlinks planted in the output directories.
+
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, OpenOptionsExt};
+
+use crate::binfmt::{self, BinfmtEntry};
+use crate::config::Config;
+
+/// Directory of runtime generators, as seen by systemd in the bottle.
+pub static RUNTIME_GENERATOR_DIR: &str = "/run/systemd/system-generators";
+
+/// Interop socket of the init of WSL, other ones go away with the session
+/// they belong to.
+pub static INTEROP_SOCKET: &str = "/run/WSL/1_interop";
+
+static GENERATOR_NAME: &str = "bottled-shell";
+static DROPIN_NAME: &str = "50-bottled-shell.conf";
+static TARGET_NAME: &str = "bottled-shell-wsl.target";
+static HEADER: &str = "# Automatically generated by bottled generator\n";
+
+#[derive(thiserror::Error, Debug)]
+pub enum GeneratorError {
+    #[error("{0} is not a directory")]
+    NotADirectory(String),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Output directories passed by systemd.
+#[derive(Debug, Clone)]
+pub struct GeneratorDirs {
+    pub normal: std::path::PathBuf,
+    pub early: std::path::PathBuf,
+    pub late: std::path::PathBuf,
+}
+
+/// What the generator found out about WSL.
+#[derive(Debug, Clone, Default)]
+pub struct WslEnvironment {
+    /// Mounts managed by WSL, drives under `/mnt/<drive>`, WSLg under
+    /// `/mnt/wslg`, and the GPU libraries under `/usr/lib/wsl`.
+    pub mounts: Vec<WslMount>,
+    /// Registration of the interop binfmt_misc entry, recorded by
+    /// `bottled start`.
+    pub interop: Option<BinfmtEntry>,
+    /// Interop socket of WSL, for running Windows programs from services.
+    pub interop_socket: Option<String>,
+    /// Whether `/etc/resolv.conf` is generated by WSL, or links to the one
+    /// generated by WSL, e.g. `/mnt/wsl/resolv.conf`.
+    pub resolv_conf_managed: bool,
+    /// Whether WSLg is mounted, and enabled in config.
+    pub wslg: bool,
+}
+
+fn unescape_mountinfo(s: &str) -> String {
+    // spaces, tabs, newlines and backslashes are escaped in octal
+    let mut out = Vec::new();
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|o| {
+            std::str::from_utf8(o).ok().and_then(|o| u8::from_str_radix(o, 8).ok())
+        });
+        match octal {
+            Some(b) if bytes[i] == b'\\' => {
+                out.push(b);
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn is_wsl_mount(mount_point: &str) -> bool {
+    let under = |dir: &str| mount_point == dir || mount_point.starts_with(&format!("{}/", dir));
+    let drive = mount_point
+        .strip_prefix("/mnt/")
+        .is_some_and(|d| d.len() == 1 && d.chars().all(|c| c.is_ascii_alphabetic()));
+    drive || under("/mnt/wslg") || under("/usr/lib/wsl")
+}
+
+/// A mount managed by WSL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WslMount {
+    pub source: String,
+    pub target: String,
+    pub fs_type: String,
+}
+
+/// Mounts managed by WSL, from the content of `/proc/self/mountinfo`.
+pub fn find_wsl_mounts(mountinfo: &str) -> Vec<WslMount> {
+    let mut mounts: Vec<WslMount> = Vec::new();
+    for line in mountinfo.lines() {
+        let parsed = line.split_once(" - ").and_then(|(mount, fs)| {
+            let target = unescape_mountinfo(mount.split(' ').nth(4)?);
+            let mut fs = fs.split(' ');
+            let fs_type = fs.next()?.to_string();
+            let source = unescape_mountinfo(fs.next()?);
+            Some(WslMount { source, target, fs_type })
+        });
+        let m = match parsed {
+            Some(m) if is_wsl_mount(&m.target) => m,
+            _ => continue,
+        };
+        // the top of stacked mounts is the one seen
+        match mounts.iter_mut().find(|o| o.target == m.target) {
+            Some(o) => *o = m,
+            None => mounts.push(m),
+        }
+    }
+    mounts
+}
+
+/// Whether the content of `/etc/resolv.conf` was generated by WSL.
+pub fn is_resolv_conf_managed(resolv_conf: &str) -> bool {
+    resolv_conf.lines().take_while(|l| l.starts_with('#')).any(|l| l.contains("generated by WSL"))
+}
+
+/// Inspect the environment seen by the generator.
+pub fn inspect(config: &Config) -> WslEnvironment {
+    let mounts = std::fs::read_to_string("/proc/self/mountinfo")
+        .map(|m| find_wsl_mounts(&m))
+        .unwrap_or_default();
+    // binfmt_misc may be an automount, which generators must not trigger,
+    // the entry recorded before starting systemd is used instead
+    let interop = if config.interop.restore {
+        binfmt::read_recorded_interop(config)
+            .map_err(|e| log::warn!("failed to read the interop registration: {}", e))
+            .ok()
+            .flatten()
+    } else {
+        None
+    };
+    let interop_socket = std::fs::symlink_metadata(INTEROP_SOCKET)
+        .ok()
+        .filter(|m| m.file_type().is_socket())
+        .map(|_| INTEROP_SOCKET.to_string());
+    // read through the symlink, if any
+    let resolv_conf_managed = std::fs::read_to_string("/etc/resolv.conf")
+        .map(|r| is_resolv_conf_managed(&r))
+        .unwrap_or(false);
+    let wslg = config.wslg.enable && std::fs::metadata(&config.wslg.path).is_ok();
+    WslEnvironment { mounts, interop, interop_socket, resolv_conf_managed, wslg }
+}
+
+/// Escape a path into a unit name, like `systemd-escape --path`.
+pub fn escape_path(path: &str) -> String {
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return "-".to_string();
+    }
+    let mut name = String::new();
+    for (i, b) in trimmed.bytes().enumerate() {
+        match b {
+            b'/' => name.push('-'),
+            b'.' if i == 0 => name.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => name.push(b as char),
+            _ => name.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    name
+}
+
+/// Quote the registration of `entry` into an `ExecStartPost=` command
+/// registering it, `None` when it can not be quoted safely.
+fn get_register_command(config: &Config, entry: &BinfmtEntry) -> Option<String> {
+    let registration = entry.registration();
+    if registration.contains(['\'', '"', '\n']) {
+        return None;
+    }
+    // backslashes, specifiers and variables are expanded by systemd
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('%', "%%").replace('$', "$$");
+    Some(format!(
+        "-/bin/sh -c \"printf '%%s' '{}' > {}/register\"",
+        escape(&registration),
+        escape(&config.interop.binfmt_misc)
+    ))
+}
+
+/// Unit of a mount of WSL, already mounted. Without default dependencies
+/// it does not conflict with umount.target, and is left to WSL on shutdown.
+fn format_mount_unit(m: &WslMount) -> String {
+    // a trailing backslash would continue the line, e.g. `C:\` of drvfs
+    let escape = |s: &str| s.replace('%', "%%").replace('\\', "\\\\");
+    format!(
+        "{}[Unit]\nDescription=WSL mount {}\nDefaultDependencies=no\nConditionPathIsMountPoint={}\n\n\
+         [Mount]\nWhat={}\nWhere={}\nType={}\n",
+        HEADER,
+        escape(&m.target),
+        escape(&m.target),
+        escape(&m.source),
+        escape(&m.target),
+        escape(&m.fs_type)
+    )
+}
+
+/// Whether `path` is a regular file written by an earlier run.
+fn is_generated(path: &std::path::Path) -> bool {
+    std::fs::symlink_metadata(path).is_ok_and(|m| m.is_file())
+        && std::fs::read_to_string(path).is_ok_and(|c| c.starts_with(HEADER))
+}
+
+/// Create the directory `path` unless it exists, refusing symlinks.
+fn create_dir(path: &std::path::Path) -> Result<(), GeneratorError> {
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => Ok(()),
+        Ok(_) => Err(GeneratorError::NotADirectory(path.display().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            // fails rather than following a symlink created meanwhile
+            std::fs::DirBuilder::new().mode(0o755).create(path)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write a new file at `path`, replacing the one of an earlier run, without
+/// following symlinks.
+fn write_file(path: &std::path::Path, content: &str) -> Result<(), GeneratorError> {
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => return Err(std::io::Error::from_raw_os_error(libc::EISDIR).into()),
+        // a symlink itself is removed, not its target
+        Ok(_) => std::fs::remove_file(path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o644)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+fn write_dropin(dir: &std::path::Path, unit: &str, content: &str) -> Result<(), GeneratorError> {
+    let dropin_dir = dir.join(format!("{}.d", unit));
+    create_dir(&dropin_dir)?;
+    log::trace!("writing drop-in of {}", unit);
+    write_file(&dropin_dir.join(DROPIN_NAME), &format!("{}{}", HEADER, content))
+}
+
+/// Write units and drop-ins for `env` into `dirs`.
+pub fn generate(config: &Config, env: &WslEnvironment, dirs: &GeneratorDirs) -> Result<(), GeneratorError> {
+    let mut mount_units = Vec::new();
+    std::fs::create_dir_all(&dirs.late)?;
+    for m in &env.mounts {
+        let unit = format!("{}.mount", escape_path(&m.target));
+        // systemd ignores drop-ins of mounts without a unit file, and units
+        // in the late directory do not override the ones from fstab
+        let path = dirs.late.join(&unit);
+        if std::fs::symlink_metadata(&path).is_ok() && !is_generated(&path) {
+            log::debug!("{} exists, skipping {}", path.display(), unit);
+            continue;
+        }
+        log::trace!("writing {}", unit);
+        write_file(&path, &format_mount_unit(m))?;
+        mount_units.push(unit);
+    }
+
+    let mut target = format!("{}[Unit]\nDescription=WSL environment\n", HEADER);
+    if !mount_units.is_empty() {
+        target.push_str(&format!("Wants={}\n", mount_units.join(" ")));
+    }
+    std::fs::create_dir_all(&dirs.normal)?;
+    write_file(&dirs.normal.join(TARGET_NAME), &target)?;
+    let wants = dirs.normal.join("multi-user.target.wants");
+    create_dir(&wants)?;
+    let link = wants.join(TARGET_NAME);
+    if std::fs::symlink_metadata(&link).is_err() {
+        std::os::unix::fs::symlink(dirs.normal.join(TARGET_NAME), &link)?;
+    }
+
+    if let Some(entry) = &env.interop {
+        match get_register_command(config, entry) {
+            // systemd-binfmt unregisters every entry when started and stopped
+            Some(command) => write_dropin(
+                &dirs.normal,
+                "systemd-binfmt.service",
+                &format!("[Service]\nExecStartPost={}\nExecStopPost={}\n", command, command),
+            )?,
+            None => log::warn!("unable to quote the registration of {}", entry.name),
+        }
+    }
+
+    if let Some(socket) = &env.interop_socket {
+        // services of users start without the environment of a WSL session
+        write_dropin(
+            &dirs.normal,
+            "user@.service",
+            &format!("[Service]\nEnvironment=\"WSL_INTEROP={}\"\n", socket.replace('%', "%%")),
+        )?;
+    }
+
+    if env.wslg {
+        // logind creates the runtime directory of each user with it
+        let bottled = std::env::current_exe()?;
+        let escape = |s: &str| {
+            s.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%").replace('$', "$$")
+        };
+        write_dropin(
+            &dirs.normal,
+            "user-runtime-dir@.service",
+            &format!("[Service]\nExecStartPost=-\"{}\" wslg %i\n", escape(&bottled.to_string_lossy())),
+        )?;
+    }
+
+    if env.resolv_conf_managed {
+        // resolved only manages /etc/resolv.conf linked to its own, e.g.
+        // /run/systemd/resolve/stub-resolv.conf, while WSL may link it to
+        // /mnt/wsl/resolv.conf as well. it is masked instead when the
+        // generator is not installed, see units::get_masked_units
+        write_dropin(
+            &dirs.normal,
+            "systemd-resolved.service",
+            "[Service]\nExecCondition=/bin/sh -c 'case \"$$(readlink /etc/resolv.conf)\" in \
+             */systemd/resolve/*) exit 0 ;; *) exit 1 ;; esac'\n",
+        )?;
+    }
+    Ok(())
+}
+
+/// Install `bottled generator` as a runtime generator of systemd.
+pub fn install_generator(bottled: &str) -> Result<(), GeneratorError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::create_dir_all(RUNTIME_GENERATOR_DIR)?;
+    let path = format!("{}/{}", RUNTIME_GENERATOR_DIR, GENERATOR_NAME);
+    log::trace!("installing {}", path);
+    let script = format!("#!/bin/sh\nexec '{}' generator \"$@\"\n", bottled.replace('\'', "'\\''"));
+    std::fs::write(&path, script)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+pub fn remove_generator() -> Result<(), GeneratorError> {
+    let path = format!("{}/{}", RUNTIME_GENERATOR_DIR, GENERATOR_NAME);
+    if std::fs::metadata(&path).is_ok() {
+        log::trace!("removing {}", path);
+        std::fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_dirs(root: &std::path::Path) -> GeneratorDirs {
+        GeneratorDirs {
+            normal: root.join("normal"),
+            early: root.join("early"),
+            late: root.join("late"),
+        }
+    }
+
+    fn get_environment() -> WslEnvironment {
+        let drive = WslMount {
+            source: "C:\\".to_string(),
+            target: "/mnt/c".to_string(),
+            fs_type: "9p".to_string(),
+        };
+        let wslg = WslMount {
+            source: "none".to_string(),
+            target: "/mnt/wslg".to_string(),
+            fs_type: "tmpfs".to_string(),
+        };
+        let interop = "enabled\ninterpreter /init\nflags: PF\noffset 0\nmagic 4d5a\n";
+        WslEnvironment {
+            mounts: vec![drive, wslg],
+            interop: Some(BinfmtEntry::parse("WSLInterop", interop).unwrap()),
+            interop_socket: Some(INTEROP_SOCKET.to_string()),
+            resolv_conf_managed: true,
+            wslg: true,
+        }
+    }
+
+    #[test]
+    fn test_escape_path() {
+        assert_eq!(escape_path("/"), "-");
+        assert_eq!(escape_path("/mnt/c"), "mnt-c");
+        assert_eq!(escape_path("/mnt/wslg/"), "mnt-wslg");
+        assert_eq!(escape_path("/usr/lib/wsl/drivers"), "usr-lib-wsl-drivers");
+        assert_eq!(escape_path("/tmp/.X11-unix"), "tmp-.X11\\x2dunix");
+        assert_eq!(escape_path("/.hidden/my disk"), "\\x2ehidden-my\\x20disk");
+    }
+
+    #[test]
+    fn test_find_wsl_mounts() {
+        let mountinfo = "\
+58 40 0:51 / /mnt/c rw,noatime - 9p C:\\134 rw,dirsync
+59 40 0:52 / /mnt/wslg rw - tmpfs none rw
+60 59 0:53 / /mnt/wslg rw - tmpfs none ro
+61 40 0:54 / /mnt/data rw - ext4 /dev/sdd rw
+62 30 0:55 / /usr/lib/wsl/drivers ro - 9p drivers rw
+";
+        let targets: Vec<_> = find_wsl_mounts(mountinfo).into_iter().map(|m| (m.target, m.source)).collect();
+        assert_eq!(
+            targets,
+            vec![
+                ("/mnt/c".to_string(), "C:\\".to_string()),
+                ("/mnt/wslg".to_string(), "none".to_string()),
+                ("/usr/lib/wsl/drivers".to_string(), "drivers".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = get_dirs(root.path());
+        let config = Config::default();
+        // twice, the files of an earlier run are replaced
+        generate(&config, &get_environment(), &dirs).unwrap();
+        generate(&config, &get_environment(), &dirs).unwrap();
+
+        let read = |p: std::path::PathBuf| std::fs::read_to_string(p).unwrap();
+        let drive = read(dirs.late.join("mnt-c.mount"));
+        assert!(drive.contains("DefaultDependencies=no\n"), "{}", drive);
+        assert!(drive.contains("What=C:\\\\\n"), "{}", drive);
+        assert!(drive.contains("Where=/mnt/c\n"), "{}", drive);
+        assert!(read(dirs.normal.join(TARGET_NAME)).contains("Wants=mnt-c.mount mnt-wslg.mount\n"));
+        let link = dirs.normal.join("multi-user.target.wants").join(TARGET_NAME);
+        assert_eq!(std::fs::read_link(link).unwrap(), dirs.normal.join(TARGET_NAME));
+
+        let dropin = |unit: &str| read(dirs.normal.join(format!("{}.d", unit)).join(DROPIN_NAME));
+        let binfmt = dropin("systemd-binfmt.service");
+        assert!(binfmt.contains("\\\\x4d\\\\x5a"), "{}", binfmt);
+        assert!(binfmt.contains("ExecStopPost=-/bin/sh"), "{}", binfmt);
+        assert!(dropin("user@.service").contains("Environment=\"WSL_INTEROP=/run/WSL/1_interop\"\n"));
+        assert!(dropin("user-runtime-dir@.service").contains("\" wslg %i\n"));
+        assert!(dropin("systemd-resolved.service").contains("ExecCondition="));
+    }
+
+    #[test]
+    fn test_generate_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = get_dirs(root.path());
+        generate(&Config::default(), &WslEnvironment::default(), &dirs).unwrap();
+        let target = std::fs::read_to_string(dirs.normal.join(TARGET_NAME)).unwrap();
+        assert!(!target.contains("Wants="));
+        assert_eq!(std::fs::read_dir(&dirs.normal).unwrap().count(), 2);
+        assert_eq!(std::fs::read_dir(&dirs.late).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_generate_keeps_units() {
+        let root = tempfile::tempdir().unwrap();
+        let dirs = get_dirs(root.path());
+        // e.g. written by systemd-fstab-generator
+        std::fs::create_dir_all(&dirs.late).unwrap();
+        std::fs::write(dirs.late.join("mnt-c.mount"), "fstab").unwrap();
+        generate(&Config::default(), &get_environment(), &dirs).unwrap();
+        assert_eq!(std::fs::read_to_string(dirs.late.join("mnt-c.mount")).unwrap(), "fstab");
+        assert!(std::fs::read_to_string(dirs.normal.join(TARGET_NAME)).unwrap().contains("Wants=mnt-wslg.mount\n"));
+    }
+
+    #[test]
+    fn test_generate_symlinks() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let victim = outside.path().join("shadow");
+        std::fs::write(&victim, "root").unwrap();
+        let dirs = get_dirs(root.path());
+        std::fs::create_dir_all(&dirs.normal).unwrap();
+        std::fs::create_dir_all(&dirs.late).unwrap();
+
+        // a symlinked unit is left alone, and a symlinked file is replaced,
+        // not written through
+        std::os::unix::fs::symlink(&victim, dirs.late.join("mnt-wslg.mount")).unwrap();
+        std::os::unix::fs::symlink(&victim, dirs.normal.join(TARGET_NAME)).unwrap();
+        // a symlinked directory is refused
+        std::os::unix::fs::symlink(outside.path(), dirs.normal.join("systemd-binfmt.service.d")).unwrap();
+
+        let result = generate(&Config::default(), &get_environment(), &dirs);
+        assert!(matches!(result, Err(GeneratorError::NotADirectory(_))), "{:?}", result);
+        assert_eq!(std::fs::read_to_string(&victim).unwrap(), "root");
+        assert_eq!(std::fs::read_dir(outside.path()).unwrap().count(), 1);
+        assert!(!std::fs::symlink_metadata(dirs.normal.join(TARGET_NAME)).unwrap().file_type().is_symlink());
+    }
+}
@@ -0,0 +1,241 @@
+//! Guard of the files generated by WSL, e.g. `/etc/resolv.conf`.
+//!
+//! The files are shared with every distro of the VM, so they are mounted
+//! read-only inside the bottle, and compared with a snapshot taken before
+//! systemd is started to report changes. The mounts do not propagate to WSL,
+//! which keeps updating the files.
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, MountConfig, MountType};
+use crate::mount::{self, MountError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum GuardError {
+    #[error(transparent)]
+    MountError(#[from] MountError),
+
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    NixErrno(#[from] nix::errno::Errno),
+}
+
+/// A guarded file, as it was before systemd is started.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub path: String,
+    /// Target of the file when it is a symlink, e.g. `/mnt/wsl/resolv.conf`.
+    pub link: Option<String>,
+    pub content: String,
+}
+
+impl Snapshot {
+    /// Take a snapshot of the file at `path`, `None` when it is missing.
+    pub fn take(path: &str) -> Result<Option<Snapshot>, GuardError> {
+        let content = match std::fs::read(path) {
+            Ok(c) => String::from_utf8_lossy(&c).to_string(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(GuardError::IOError(e)),
+        };
+        let link = std::fs::read_link(path).ok().map(|l| l.to_string_lossy().to_string());
+        Ok(Some(Snapshot { path: path.to_string(), link, content }))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GuardState {
+    /// Mounted read-only, and unchanged.
+    Protected,
+    /// The content or the symlink differs from the snapshot, whether it was
+    /// changed in the bottle or rewritten by WSL.
+    Changed,
+    /// Unchanged, but no longer mounted read-only, or a symlink, which can
+    /// still be replaced since only its target is mounted read-only.
+    Unprotected,
+    /// Removed since the snapshot.
+    Missing,
+}
+
+impl std::fmt::Display for GuardState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            GuardState::Protected => "protected",
+            GuardState::Changed => "changed",
+            GuardState::Unprotected => "unprotected",
+            GuardState::Missing => "missing",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct GuardStatus {
+    pub path: String,
+    pub state: GuardState,
+}
+
+/// Compare the current state of a guarded file with its snapshot.
+pub fn check_snapshot(snapshot: &Snapshot, current: Option<&Snapshot>, read_only: bool) -> GuardState {
+    match current {
+        None => GuardState::Missing,
+        Some(c) if c != snapshot => GuardState::Changed,
+        Some(c) if !read_only || c.link.is_some() => GuardState::Unprotected,
+        Some(_) => GuardState::Protected,
+    }
+}
+
+fn get_snapshot_file(config: &Config) -> String {
+    format!("{}/guard.json", config.bottle.run_dir)
+}
+
+/// Take snapshots of the guarded files, and save them under the runtime
+/// directory. Returns the paths of the files to protect, missing ones are
+/// skipped.
+pub fn save_snapshots(config: &Config) -> Result<Vec<String>, GuardError> {
+    let mut snapshots = Vec::new();
+    for f in &config.guard.files {
+        match Snapshot::take(f)? {
+            Some(s) => snapshots.push(s),
+            None => log::debug!("{} missing, not guarded", f),
+        }
+    }
+    std::fs::create_dir_all(&config.bottle.run_dir)?;
+    std::fs::write(get_snapshot_file(config), serde_json::to_string(&snapshots)?)?;
+    Ok(snapshots.into_iter().map(|s| s.path).collect())
+}
+
+pub fn read_snapshots(config: &Config) -> Result<Vec<Snapshot>, GuardError> {
+    match std::fs::read_to_string(get_snapshot_file(config)) {
+        Ok(content) => Ok(serde_json::from_str(&content)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(GuardError::IOError(e)),
+    }
+}
+
+pub fn remove_snapshots(config: &Config) -> Result<(), GuardError> {
+    let path = get_snapshot_file(config);
+    if std::fs::metadata(&path).is_ok() {
+        log::trace!("removing {}", path);
+        std::fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+/// Mount the file at `path` read-only onto itself, symlinks are followed, so
+/// the link itself is left writable.
+pub fn protect(path: &str) -> Result<(), GuardError> {
+    let m = MountConfig {
+        kind: MountType::Bind,
+        source: Some(path.to_string()),
+        target: path.to_string(),
+        options: None,
+        read_only: true,
+        recursive: false,
+        propagation: None,
+    };
+    mount::apply_mount(&m)?;
+    Ok(())
+}
+
+/// Whether `path` is a read-only mount point, from the content of
+/// `/proc/<pid>/mountinfo`.
+pub fn is_read_only_mount(mountinfo: &str, path: &str) -> bool {
+    // the last mount on a mount point is the one seen
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let mount_point = fields.nth(4)?;
+            let options = fields.next()?;
+            Some((mount_point.replace("\\040", " "), options))
+        })
+        .rfind(|(mount_point, _)| mount_point == path)
+        .is_some_and(|(_, options)| options.split(',').any(|o| o == "ro"))
+}
+
+/// State of the guarded files, as seen inside the bottle of
+/// systemd(PID=`pid`).
+pub fn get_guard_status(config: &Config, pid: libc::pid_t) -> Result<Vec<GuardStatus>, GuardError> {
+    use std::os::unix::io::AsRawFd;
+    use nix::sched::CloneFlags;
+
+    let snapshots = read_snapshots(config)?;
+    if snapshots.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // /proc inside the bottle does not show the caller
+    let mountinfo = std::fs::read_to_string(format!("/proc/{}/mountinfo", pid))?;
+
+    // the mount namespace is joined by a thread with its own filesystem
+    // attributes, without affecting the caller
+    let ns = std::fs::File::open(format!("/proc/{}/ns/mnt", pid))?;
+    std::thread::spawn(move || -> Result<Vec<GuardStatus>, GuardError> {
+        nix::sched::unshare(CloneFlags::CLONE_FS)?;
+        nix::sched::setns(ns.as_raw_fd(), CloneFlags::CLONE_NEWNS)?;
+        let mut status = Vec::new();
+        for s in snapshots {
+            let current = Snapshot::take(&s.path)?;
+            let read_only = std::fs::canonicalize(&s.path)
+                .is_ok_and(|p| is_read_only_mount(&mountinfo, &p.to_string_lossy()));
+            let state = check_snapshot(&s, current.as_ref(), read_only);
+            status.push(GuardStatus { path: s.path, state });
+        }
+        Ok(status)
+    })
+    .join()
+    .unwrap_or_else(|_| Err(GuardError::IOError(std::io::Error::from(std::io::ErrorKind::Other))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_snapshot(link: Option<&str>, content: &str) -> Snapshot {
+        Snapshot {
+            path: "/etc/resolv.conf".to_string(),
+            link: link.map(|l| l.to_string()),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_check_snapshot() {
+        let snapshot = get_snapshot(None, "nameserver 172.20.0.1\n");
+        assert_eq!(check_snapshot(&snapshot, Some(&snapshot), true), GuardState::Protected);
+        assert_eq!(check_snapshot(&snapshot, Some(&snapshot), false), GuardState::Unprotected);
+        assert_eq!(check_snapshot(&snapshot, None, true), GuardState::Missing);
+        let changed = get_snapshot(None, "nameserver 127.0.0.53\n");
+        assert_eq!(check_snapshot(&snapshot, Some(&changed), true), GuardState::Changed);
+        let relinked = get_snapshot(Some("../run/systemd/resolve/stub-resolv.conf"), "nameserver 172.20.0.1\n");
+        assert_eq!(check_snapshot(&snapshot, Some(&relinked), true), GuardState::Changed);
+    }
+
+    #[test]
+    fn test_check_snapshot_symlink() {
+        let snapshot = get_snapshot(Some("/mnt/wsl/resolv.conf"), "nameserver 172.20.0.1\n");
+        assert_eq!(check_snapshot(&snapshot, Some(&snapshot), true), GuardState::Unprotected);
+    }
+
+    #[test]
+    fn test_is_read_only_mount() {
+        let mountinfo = "\
+30 1 8:32 / / rw,relatime shared:1 - ext4 /dev/sdc rw
+40 30 8:32 /etc/resolv.conf /etc/resolv.conf ro,relatime shared:1 - ext4 /dev/sdc rw
+41 30 8:32 /etc/hosts /etc/hosts ro,relatime shared:1 - ext4 /dev/sdc rw
+42 41 8:32 /etc/hosts /etc/hosts rw,relatime shared:1 - ext4 /dev/sdc rw
+43 30 0:50 / /mnt/my\\040files ro - tmpfs none rw
+";
+        assert!(is_read_only_mount(mountinfo, "/etc/resolv.conf"));
+        assert!(!is_read_only_mount(mountinfo, "/etc/hosts"));
+        assert!(is_read_only_mount(mountinfo, "/mnt/my files"));
+        assert!(!is_read_only_mount(mountinfo, "/etc"));
+        assert!(!is_read_only_mount("", "/etc/resolv.conf"));
+    }
+}
@@ -1,3 +1,19 @@
+pub mod binfmt;
+pub mod cgroup;
+pub mod config;
+pub mod dbus;
+pub mod doctor;
 pub mod env;
+pub mod exec;
+pub mod generator;
+pub mod guard;
+pub mod manager;
+pub mod mount;
+pub mod pam;
+pub mod pidfd;
 pub mod systemd;
-pub mod shell;
\ No newline at end of file
+pub mod shell;
+pub mod status;
+pub mod supervisor;
+pub mod units;
+pub mod wslg;
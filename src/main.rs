@@ -1,5 +1,5 @@
 fn main() {
-    if let Err(_) = std::env::var("BOTTLED_SHELL_LOG") {
+    if std::env::var("BOTTLED_SHELL_LOG").is_err() {
         std::env::set_var("BOTTLED_SHELL_LOG", "info");
     }
     pretty_env_logger::init_custom_env("BOTTLED_SHELL_LOG");
@@ -29,7 +29,7 @@ fn main() {
     }
 
     let bottled_cmd = if let Some((c, _)) = clap::crate_name!().rsplit_once('-') {
-        c.clone()
+        c
     } else {
         clap::crate_name!()
     };
@@ -41,7 +41,7 @@ fn main() {
     let mut shell = "bash";
     if let Some(b) = app.get_bin_name() {
         if let Some((_, s)) = b.rsplit_once('-') {
-            shell = s.clone();
+            shell = s;
         }
     }
     let mut args: Vec<std::ffi::CString> = vec![
@@ -58,8 +58,10 @@ fn main() {
     }
 
     log::trace!("executing bottled shell: {:?}", args);
-    nix::unistd::execv(
-        &std::ffi::CString::new(bottled_cmd_path.as_str()).unwrap().as_c_str(),
+    let Err(e) = nix::unistd::execv(
+        std::ffi::CString::new(bottled_cmd_path.as_str()).unwrap().as_c_str(),
         &args
-    ).unwrap();
+    );
+    eprintln!("failed to execute {}: {}", bottled_cmd_path, e);
+    std::process::exit(1);
 }
@@ -0,0 +1,253 @@
+use crate::config::Config;
+use crate::dbus::{Connection, DBusError, Value};
+
+const SYSTEMD1: &str = "org.freedesktop.systemd1";
+const SYSTEMD1_PATH: &str = "/org/freedesktop/systemd1";
+const MANAGER: &str = "org.freedesktop.systemd1.Manager";
+const UNIT: &str = "org.freedesktop.systemd1.Unit";
+
+/// Socket of systemd accepting direct connections from root, available
+/// before the system bus is started.
+const PRIVATE_SOCKET: &str = "/run/systemd/private";
+
+/// An entry returned by `ListUnits`.
+#[derive(Debug, Clone)]
+pub struct UnitStatus {
+    pub name: String,
+    pub description: String,
+    pub load_state: String,
+    pub active_state: String,
+    pub sub_state: String,
+    pub path: String,
+}
+
+/// Client of `org.freedesktop.systemd1.Manager` of bottled systemd.
+pub struct SystemdManager {
+    connection: Connection,
+    /// Name of systemd on the bus, `None` when connected to systemd directly.
+    destination: Option<&'static str>,
+}
+
+impl SystemdManager {
+    /// Connect through the system bus inside the mount namespace of
+    /// systemd(PID=`pid`), or of the caller when `pid` is `None`. Falls back
+    /// to the private socket of systemd when the bus is not available, e.g.
+    /// while booting or when no bus daemon is installed.
+    pub fn connect(config: &Config, pid: Option<libc::pid_t>) -> Result<SystemdManager, DBusError> {
+        let socket = match pid {
+            Some(pid) => format!("/proc/{}/root{}", pid, config.systemd.bus_socket),
+            None => config.systemd.bus_socket.clone(),
+        };
+        match SystemdManager::connect_to(&socket) {
+            Ok(m) => Ok(m),
+            Err(e) => {
+                log::trace!("system bus not available, trying the private socket: {}", e);
+                SystemdManager::connect_private(pid)
+            }
+        }
+    }
+
+    /// Connect through the bus listening on `socket`.
+    pub fn connect_to(socket: &str) -> Result<SystemdManager, DBusError> {
+        Ok(SystemdManager {
+            connection: Connection::connect_bus(socket)?,
+            destination: Some(SYSTEMD1),
+        })
+    }
+
+    /// Connect to systemd(PID=`pid`) directly through its private socket, or
+    /// to the systemd of the caller when `pid` is `None`.
+    ///
+    /// systemd refuses peers outside of its PID namespace, so the connection
+    /// is made by a child forked into the namespace, and passed back once
+    /// authenticated.
+    pub fn connect_private(pid: Option<libc::pid_t>) -> Result<SystemdManager, DBusError> {
+        let connection = match pid {
+            Some(pid) => connect_in_namespace(pid, &format!("/proc/{}/root{}", pid, PRIVATE_SOCKET))?,
+            None => Connection::connect_peer(PRIVATE_SOCKET)?,
+        };
+        Ok(SystemdManager {
+            connection,
+            destination: None,
+        })
+    }
+
+    fn call(&mut self, member: &str, args: &[Value]) -> Result<Vec<Value>, DBusError> {
+        self.connection.call(self.destination, SYSTEMD1_PATH, MANAGER, member, args)
+    }
+
+    fn first(reply: Vec<Value>, member: &str) -> Result<Value, DBusError> {
+        reply.into_iter().next()
+            .ok_or_else(|| DBusError::MalformedMessage(format!("empty reply of {}", member)))
+    }
+
+    pub fn power_off(&mut self) -> Result<(), DBusError> {
+        self.call("PowerOff", &[])?;
+        Ok(())
+    }
+
+    /// Reload the configuration of the manager, like `systemctl daemon-reload`.
+    pub fn reload(&mut self) -> Result<(), DBusError> {
+        self.call("Reload", &[])?;
+        Ok(())
+    }
+
+    /// Object path of a loaded unit.
+    pub fn get_unit(&mut self, name: &str) -> Result<String, DBusError> {
+        let reply = self.call("GetUnit", &[Value::String(name.to_string())])?;
+        let path = SystemdManager::first(reply, "GetUnit")?;
+        Ok(path.as_str().unwrap_or_default().to_string())
+    }
+
+    /// `ActiveState` of a unit, e.g. `active` or `activating`.
+    pub fn get_unit_active_state(&mut self, name: &str) -> Result<String, DBusError> {
+        let path = self.get_unit(name)?;
+        let state = self.connection.get_property(self.destination, &path, UNIT, "ActiveState")?;
+        Ok(state.as_str().unwrap_or_default().to_string())
+    }
+
+    pub fn list_units(&mut self) -> Result<Vec<UnitStatus>, DBusError> {
+        let reply = self.call("ListUnits", &[])?;
+        let units = SystemdManager::first(reply, "ListUnits")?;
+        let field = |u: &[Value], i: usize| u.get(i).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        Ok(units
+            .as_array()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|u| u.as_struct())
+            .map(|u| UnitStatus {
+                name: field(u, 0),
+                description: field(u, 1),
+                load_state: field(u, 2),
+                active_state: field(u, 3),
+                sub_state: field(u, 4),
+                path: field(u, 6),
+            })
+            .collect())
+    }
+
+    /// Update the environment passed to units, `assignments` are `KEY=VALUE`.
+    pub fn set_environment(&mut self, assignments: &[String]) -> Result<(), DBusError> {
+        let assignments = assignments.iter().map(|a| Value::String(a.clone())).collect();
+        self.call("SetEnvironment", &[Value::Array("s".to_string(), assignments)])?;
+        Ok(())
+    }
+
+    /// Start a transient unit, returns the object path of the job.
+    pub fn start_transient_unit(&mut self, name: &str, mode: &str, properties: Vec<(String, Value)>) -> Result<String, DBusError> {
+        let properties = properties
+            .into_iter()
+            .map(|(k, v)| Value::Struct(vec![Value::String(k), Value::Variant(Box::new(v))]))
+            .collect();
+        let reply = self.call("StartTransientUnit", &[
+            Value::String(name.to_string()),
+            Value::String(mode.to_string()),
+            Value::Array("(sv)".to_string(), properties),
+            Value::Array("(sa(sv))".to_string(), Vec::new()),
+        ])?;
+        let job = SystemdManager::first(reply, "StartTransientUnit")?;
+        Ok(job.as_str().unwrap_or_default().to_string())
+    }
+
+    /// `SystemState` of the manager, e.g. `starting`, `running` or `degraded`.
+    pub fn system_state(&mut self) -> Result<String, DBusError> {
+        let state = self.connection.get_property(self.destination, SYSTEMD1_PATH, MANAGER, "SystemState")?;
+        Ok(state.as_str().unwrap_or_default().to_string())
+    }
+}
+
+/// Connect to the peer at `socket` from a child in the PID namespace of
+/// `pid`, the authenticated connection is passed back through `SCM_RIGHTS`.
+fn connect_in_namespace(pid: libc::pid_t, socket: &str) -> Result<Connection, DBusError> {
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+    use nix::fcntl::OFlag;
+    use nix::sched::CloneFlags;
+    use nix::sys::socket::{AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType};
+    use nix::sys::stat::Mode;
+    use nix::sys::uio::IoVec;
+    use nix::unistd::ForkResult;
+
+    let open = |path: &str| nix::fcntl::open(path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty());
+    let close = |fds: &[RawFd]| fds.iter().for_each(|fd| { let _ = nix::unistd::close(*fd); });
+    let self_ns = open("/proc/self/ns/pid").map_err(std::io::Error::from)?;
+    let ns = match open(&format!("/proc/{}/ns/pid", pid)) {
+        Ok(fd) => fd,
+        Err(e) => {
+            close(&[self_ns]);
+            return Err(DBusError::IOError(e.into()));
+        }
+    };
+    let (parent_fd, child_fd) = match nix::sys::socket::socketpair(
+        AddressFamily::Unix, SockType::Stream, None, SockFlag::SOCK_CLOEXEC,
+    ) {
+        Ok(fds) => fds,
+        Err(e) => {
+            close(&[self_ns, ns]);
+            return Err(DBusError::IOError(e.into()));
+        }
+    };
+
+    // only children are created in the namespace, the caller stays outside
+    let forked = nix::sched::setns(ns, CloneFlags::CLONE_NEWPID)
+        .and_then(|_| unsafe { nix::unistd::fork() });
+    let restored = nix::sched::setns(self_ns, CloneFlags::CLONE_NEWPID);
+    if let Ok(ForkResult::Child) = forked {
+        let code = match Connection::connect_peer(socket) {
+            Ok(c) => {
+                let fds = [c.as_stream().as_raw_fd()];
+                let message = [IoVec::from_slice(b"\0")];
+                let control = [ControlMessage::ScmRights(&fds)];
+                match nix::sys::socket::sendmsg(child_fd, &message, &control, MsgFlags::empty(), None) {
+                    Ok(_) => libc::EXIT_SUCCESS,
+                    Err(_) => libc::EXIT_FAILURE,
+                }
+            }
+            Err(e) => {
+                let _ = nix::unistd::write(child_fd, e.to_string().as_bytes());
+                libc::EXIT_FAILURE
+            }
+        };
+        std::process::exit(code);
+    }
+    close(&[self_ns, ns, child_fd]);
+    let child = match forked {
+        Ok(ForkResult::Parent { child }) => child,
+        _ => {
+            close(&[parent_fd]);
+            return Err(DBusError::IOError(forked.err().unwrap_or(nix::errno::Errno::EINVAL).into()));
+        }
+    };
+    if let Err(e) = restored {
+        log::warn!("failed to restore the PID namespace for children: {}", e);
+    }
+
+    let mut buffer = [0u8; 512];
+    let mut space = nix::cmsg_space!([RawFd; 1]);
+    let received = nix::sys::socket::recvmsg(
+        parent_fd,
+        &[IoVec::from_mut_slice(&mut buffer)],
+        Some(&mut space),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )
+    .map(|m| {
+        let fd = m.cmsgs().find_map(|c| match c {
+            ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+            _ => None,
+        });
+        (fd, m.bytes)
+    });
+    close(&[parent_fd]);
+    let _ = nix::sys::wait::waitpid(child, None);
+
+    match received {
+        Ok((Some(fd), _)) => {
+            log::trace!("connected {} from PID namespace of {}", socket, pid);
+            Ok(Connection::from_authenticated(unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) }))
+        }
+        Ok((None, n)) => Err(DBusError::IOError(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            String::from_utf8_lossy(&buffer[..n]).to_string(),
+        ))),
+        Err(e) => Err(DBusError::IOError(e.into())),
+    }
+}
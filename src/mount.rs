@@ -0,0 +1,93 @@
+//! Mounts configured in `[[mount]]`, applied inside the bottle.
+//!
+//! `/` of the bottle is already a recursive slave of WSL when they are
+//! applied, so none of them propagates back to WSL, and `propagation` only
+//! matters among the mounts of the bottle.
+
+use nix::mount::MsFlags;
+
+use crate::config::{MountConfig, MountType, Propagation};
+
+#[derive(thiserror::Error, Debug)]
+pub enum MountError {
+    #[error("invalid mount, {0}")]
+    InvalidMount(String),
+
+    #[error(transparent)]
+    NixErrno(#[from] nix::errno::Errno),
+}
+
+/// Check an entry without mounting anything.
+pub fn check_mount(m: &MountConfig) -> Result<(), MountError> {
+    if !m.target.starts_with('/') {
+        return Err(MountError::InvalidMount(format!("target {} is not absolute", m.target)));
+    }
+    match m.kind {
+        MountType::Bind if m.source.is_none() => {
+            Err(MountError::InvalidMount(format!("{}: source is required", m)))
+        }
+        MountType::Propagation if m.propagation.is_none() => {
+            Err(MountError::InvalidMount(format!("{}: propagation is required", m)))
+        }
+        _ if m.kind != MountType::Tmpfs && m.options.is_some() => {
+            Err(MountError::InvalidMount(format!("{}: options are only for tmpfs", m)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn set_propagation(target: &str, propagation: Propagation, recursive: bool) -> nix::Result<()> {
+    let mut flags = match propagation {
+        Propagation::Shared => MsFlags::MS_SHARED,
+        Propagation::Slave => MsFlags::MS_SLAVE,
+        Propagation::Private => MsFlags::MS_PRIVATE,
+        Propagation::Unbindable => MsFlags::MS_UNBINDABLE,
+    };
+    if recursive {
+        flags |= MsFlags::MS_REC;
+    }
+    log::trace!("setting propagation of {} to {:?}", target, propagation);
+    nix::mount::mount(None as Option<&str>, target, None as Option<&str>, flags, None as Option<&str>)
+}
+
+/// Apply an entry, targets must exist already.
+pub fn apply_mount(m: &MountConfig) -> Result<(), MountError> {
+    check_mount(m)?;
+    let target = m.target.as_str();
+
+    match m.kind {
+        MountType::Bind => {
+            let source = m.source.as_deref().unwrap();
+            let mut flags = MsFlags::MS_BIND;
+            if m.recursive {
+                flags |= MsFlags::MS_REC;
+            }
+            log::trace!("bind mounting {} to {}", source, target);
+            nix::mount::mount(Some(source), target, None as Option<&str>, flags, None as Option<&str>)?;
+            // read-only is only applied by remounting a bind mount
+            if m.read_only {
+                nix::mount::mount(
+                    None as Option<&str>,
+                    target,
+                    None as Option<&str>,
+                    MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+                    None as Option<&str>,
+                )?;
+            }
+        }
+        MountType::Tmpfs => {
+            let mut flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+            if m.read_only {
+                flags |= MsFlags::MS_RDONLY;
+            }
+            log::trace!("mounting tmpfs at {}", target);
+            nix::mount::mount(Some("tmpfs"), target, Some("tmpfs"), flags, m.options.as_deref())?;
+        }
+        MountType::Propagation => (),
+    }
+
+    if let Some(p) = m.propagation {
+        set_propagation(target, p, m.recursive)?;
+    }
+    Ok(())
+}
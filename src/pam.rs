@@ -0,0 +1,257 @@
+//! PAM bindings, loaded at runtime so bottled still runs without libpam.
+
+use std::ffi::{CStr, CString};
+use libc::{c_char, c_int, c_void};
+
+const LIBPAM: &str = "libpam.so.0";
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_CONV_ERR: c_int = 19;
+
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+const PAM_ESTABLISH_CRED: c_int = 0x2;
+const PAM_DELETE_CRED: c_int = 0x4;
+
+pub const PAM_TTY: c_int = 3;
+pub const PAM_RUSER: c_int = 8;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PamError {
+    #[error("failed to load {0}")]
+    LibraryNotFound(String),
+
+    #[error("{0} failed: {1}")]
+    CallFailed(String, String),
+}
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type Conversation = extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: Conversation,
+    appdata_ptr: *mut c_void,
+}
+
+/// Passes informational messages to the user, and refuses any prompt since
+/// bottled never authenticates.
+extern "C" fn conversation(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    _appdata_ptr: *mut c_void,
+) -> c_int {
+    if num_msg <= 0 {
+        return PAM_CONV_ERR;
+    }
+    let responses = unsafe {
+        libc::calloc(num_msg as usize, std::mem::size_of::<PamResponse>()) as *mut PamResponse
+    };
+    if responses.is_null() {
+        return PAM_BUF_ERR;
+    }
+    for i in 0..num_msg as usize {
+        let m = unsafe { &**msg.add(i) };
+        let text = unsafe { CStr::from_ptr(m.msg) }.to_string_lossy();
+        match m.msg_style {
+            PAM_ERROR_MSG | PAM_TEXT_INFO => eprintln!("{}", text),
+            _ => {
+                log::error!("unexpected PAM prompt: {}", text);
+                unsafe { libc::free(responses as *mut c_void); }
+                return PAM_CONV_ERR;
+            }
+        }
+    }
+    unsafe { *resp = responses; }
+    PAM_SUCCESS
+}
+
+struct Library {
+    handle: *mut c_void,
+}
+
+impl Library {
+    fn open() -> Result<Library, PamError> {
+        let name = CString::new(LIBPAM).unwrap();
+        let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL) };
+        if handle.is_null() {
+            return Err(PamError::LibraryNotFound(LIBPAM.to_string()));
+        }
+        Ok(Library { handle })
+    }
+
+    fn symbol<T>(&self, name: &str) -> Result<T, PamError> {
+        let symbol = CString::new(name).unwrap();
+        let ptr = unsafe { libc::dlsym(self.handle, symbol.as_ptr()) };
+        if ptr.is_null() {
+            return Err(PamError::LibraryNotFound(format!("{}({})", LIBPAM, name)));
+        }
+        Ok(unsafe { std::mem::transmute_copy::<*mut c_void, T>(&ptr) })
+    }
+}
+
+type PamStart = extern "C" fn(*const c_char, *const c_char, *const PamConv, *mut *mut c_void) -> c_int;
+type PamEnd = extern "C" fn(*mut c_void, c_int) -> c_int;
+type PamFlagged = extern "C" fn(*mut c_void, c_int) -> c_int;
+type PamSetItem = extern "C" fn(*mut c_void, c_int, *const c_void) -> c_int;
+type PamPutenv = extern "C" fn(*mut c_void, *const c_char) -> c_int;
+type PamGetenvlist = extern "C" fn(*mut c_void) -> *mut *mut c_char;
+type PamStrerror = extern "C" fn(*mut c_void, c_int) -> *const c_char;
+
+/// A PAM transaction, ended when dropped.
+pub struct Pam {
+    handle: *mut c_void,
+    status: c_int,
+    session_opened: bool,
+    credential_established: bool,
+    _conv: Box<PamConv>,
+    _library: Library,
+    end: PamEnd,
+    acct_mgmt: PamFlagged,
+    setcred: PamFlagged,
+    open_session: PamFlagged,
+    close_session: PamFlagged,
+    set_item: PamSetItem,
+    putenv: PamPutenv,
+    getenvlist: PamGetenvlist,
+    strerror: PamStrerror,
+}
+
+impl Pam {
+    pub fn start(service: &str, user: &str) -> Result<Pam, PamError> {
+        let library = Library::open()?;
+        let start: PamStart = library.symbol("pam_start")?;
+        // every symbol is resolved first, so that a started transaction is
+        // always ended
+        let end: PamEnd = library.symbol("pam_end")?;
+        let acct_mgmt: PamFlagged = library.symbol("pam_acct_mgmt")?;
+        let setcred: PamFlagged = library.symbol("pam_setcred")?;
+        let open_session: PamFlagged = library.symbol("pam_open_session")?;
+        let close_session: PamFlagged = library.symbol("pam_close_session")?;
+        let set_item: PamSetItem = library.symbol("pam_set_item")?;
+        let putenv: PamPutenv = library.symbol("pam_putenv")?;
+        let getenvlist: PamGetenvlist = library.symbol("pam_getenvlist")?;
+        let strerror: PamStrerror = library.symbol("pam_strerror")?;
+
+        let conv = Box::new(PamConv { conv: conversation, appdata_ptr: std::ptr::null_mut() });
+        let service_c = CString::new(service).unwrap();
+        let user_c = CString::new(user).unwrap();
+        let mut handle = std::ptr::null_mut();
+        let status = start(service_c.as_ptr(), user_c.as_ptr(), &*conv, &mut handle);
+        if status != PAM_SUCCESS || handle.is_null() {
+            return Err(PamError::CallFailed("pam_start".to_string(), format!("error {}", status)));
+        }
+        log::trace!("PAM transaction started: service={}, user={}", service, user);
+
+        Ok(Pam {
+            handle,
+            status: PAM_SUCCESS,
+            session_opened: false,
+            credential_established: false,
+            end,
+            acct_mgmt,
+            setcred,
+            open_session,
+            close_session,
+            set_item,
+            putenv,
+            getenvlist,
+            strerror,
+            _conv: conv,
+            _library: library,
+        })
+    }
+
+    fn check(&mut self, call: &str, status: c_int) -> Result<(), PamError> {
+        self.status = status;
+        if status == PAM_SUCCESS {
+            log::trace!("{}: success", call);
+            return Ok(());
+        }
+        let message = unsafe { CStr::from_ptr((self.strerror)(self.handle, status)) }
+            .to_string_lossy()
+            .to_string();
+        Err(PamError::CallFailed(call.to_string(), message))
+    }
+
+    pub fn set_item(&mut self, item: c_int, value: &str) -> Result<(), PamError> {
+        let value = CString::new(value).unwrap();
+        let status = (self.set_item)(self.handle, item, value.as_ptr() as *const c_void);
+        self.check("pam_set_item", status)
+    }
+
+    pub fn putenv(&mut self, assignment: &str) -> Result<(), PamError> {
+        let assignment = CString::new(assignment).unwrap();
+        let status = (self.putenv)(self.handle, assignment.as_ptr());
+        self.check("pam_putenv", status)
+    }
+
+    pub fn acct_mgmt(&mut self) -> Result<(), PamError> {
+        let status = (self.acct_mgmt)(self.handle, 0);
+        self.check("pam_acct_mgmt", status)
+    }
+
+    pub fn establish_credential(&mut self) -> Result<(), PamError> {
+        let status = (self.setcred)(self.handle, PAM_ESTABLISH_CRED);
+        self.check("pam_setcred", status)?;
+        self.credential_established = true;
+        Ok(())
+    }
+
+    pub fn open_session(&mut self) -> Result<(), PamError> {
+        let status = (self.open_session)(self.handle, 0);
+        self.check("pam_open_session", status)?;
+        self.session_opened = true;
+        Ok(())
+    }
+
+    /// Environment variables set by PAM modules, as `KEY=VALUE`.
+    pub fn getenvlist(&self) -> Vec<String> {
+        let mut envs = Vec::new();
+        let list = (self.getenvlist)(self.handle);
+        if list.is_null() {
+            return envs;
+        }
+        let mut i = 0;
+        loop {
+            let e = unsafe { *list.add(i) };
+            if e.is_null() {
+                break;
+            }
+            envs.push(unsafe { CStr::from_ptr(e) }.to_string_lossy().to_string());
+            unsafe { libc::free(e as *mut c_void); }
+            i += 1;
+        }
+        unsafe { libc::free(list as *mut c_void); }
+        envs
+    }
+}
+
+impl Drop for Pam {
+    fn drop(&mut self) {
+        if self.session_opened {
+            let status = (self.close_session)(self.handle, 0);
+            log::trace!("pam_close_session: {}", status);
+        }
+        if self.credential_established {
+            let status = (self.setcred)(self.handle, PAM_DELETE_CRED);
+            log::trace!("pam_setcred(PAM_DELETE_CRED): {}", status);
+        }
+        (self.end)(self.handle, self.status);
+        log::trace!("PAM transaction ended");
+    }
+}
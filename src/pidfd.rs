@@ -0,0 +1,71 @@
+//! Process file descriptors, referring to a process rather than a PID which
+//! may be reused once the process exited.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// A pidfd, closed when dropped.
+#[derive(Debug)]
+pub struct PidFd {
+    pid: libc::pid_t,
+    fd: RawFd,
+}
+
+impl PidFd {
+    pub fn open(pid: libc::pid_t) -> nix::Result<PidFd> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd < 0 {
+            return Err(nix::errno::Errno::last());
+        }
+        log::trace!("opened pidfd of PID={}", pid);
+        Ok(PidFd { pid, fd: fd as RawFd })
+    }
+
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    pub fn send_signal(&self, signal: libc::c_int) -> nix::Result<()> {
+        log::trace!("sending signal {} to PID={}", signal, self.pid);
+        let result = unsafe {
+            libc::syscall(libc::SYS_pidfd_send_signal, self.fd, signal, std::ptr::null::<libc::siginfo_t>(), 0)
+        };
+        if result < 0 {
+            return Err(nix::errno::Errno::last());
+        }
+        Ok(())
+    }
+
+    /// Wait until the process exited, returns `false` on timeout.
+    pub fn wait_exit(&self, timeout: std::time::Duration) -> nix::Result<bool> {
+        use nix::poll::{PollFd, PollFlags};
+
+        let start = std::time::Instant::now();
+        let mut fds = [PollFd::new(self.fd, PollFlags::POLLIN)];
+        loop {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            let millis = remaining.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            match nix::poll::poll(&mut fds, millis) {
+                Ok(n) => return Ok(n > 0),
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Whether the process is still running, zombies are not.
+    pub fn is_alive(&self) -> bool {
+        !self.wait_exit(std::time::Duration::ZERO).unwrap_or(true)
+    }
+}
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for PidFd {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.fd);
+    }
+}
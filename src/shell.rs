@@ -1,11 +1,26 @@
+use crate::config::{Config, SessionType};
 use crate::env;
+use crate::exec::{self, Credential};
+use crate::pam::{self, Pam};
 use crate::systemd;
+use crate::wslg;
+
+static DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
 
 #[derive(thiserror::Error, Debug)]
 pub enum ShellError {
     #[error("shell not found for '{0}'")]
     ShellNotFound(String),
 
+    #[error(transparent)]
+    ExecError(#[from] exec::ExecError),
+
+    #[error(transparent)]
+    PamError(#[from] pam::PamError),
+
+    #[error(transparent)]
+    SystemdError(#[from] systemd::SystemdError),
+
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 
@@ -13,7 +28,7 @@ pub enum ShellError {
     NixErrno(#[from] nix::errno::Errno),
 }
 
-fn get_shell_path(shell: &String) -> Result<String, ShellError> {
+fn get_shell_path(shell: &str) -> Result<String, ShellError> {
     use std::io::BufRead;
 
     for l in std::io::BufReader::new(std::fs::File::open("/etc/shells")?).lines() {
@@ -25,22 +40,159 @@ fn get_shell_path(shell: &String) -> Result<String, ShellError> {
             }
         }
     }
-    Err(ShellError::ShellNotFound(shell.clone()))
+    Err(ShellError::ShellNotFound(shell.to_string()))
+}
+
+fn launch_machinectl_session(config: &Config, pw_name: &str, bottled_shell_path: &str, args: &[String]) -> Result<(), ShellError> {
+    use std::ffi::CString;
+
+    log::trace!("associating with bottled systemd");
+    systemd::associate_with_systemd(config)?;
+
+    let executable = systemd::get_machinectl_bin(config)?;
+    let mut expanded_args: Vec<CString> = vec![
+        CString::new("machinectl").unwrap(),
+        CString::new("shell").unwrap(),
+    ];
+    expanded_args.push(CString::new("-q").unwrap());
+    for e in env::get_preserved_env(config) {
+        expanded_args.push(CString::new("-E").unwrap());
+        expanded_args.push(CString::new(e).unwrap());
+    }
+    expanded_args.push(CString::new(format!("{}@.host", pw_name)).unwrap());
+    if !args.is_empty() {
+        expanded_args.push(CString::new(bottled_shell_path).unwrap());
+        for v in args {
+            expanded_args.push(CString::new(v.as_str()).unwrap());
+        }
+    }
+
+    log::trace!("launch session: {} {:?}", executable, expanded_args);
+    nix::unistd::execv(&CString::new(executable).unwrap(), &expanded_args)?;
+    unreachable!();
+}
+
+fn exec_pam_shell(credential: &Credential, executable: &str, shell: &str, args: &[String], envs: &[String]) -> Result<(), ShellError> {
+    use std::collections::BTreeMap;
+    use std::ffi::CString;
+
+    log::trace!("releasing privilege");
+    nix::unistd::setgid(credential.gid)?;
+    nix::unistd::setuid(credential.uid)?;
+
+    let mut environment: BTreeMap<String, String> = BTreeMap::new();
+    environment.insert("PATH".to_string(), DEFAULT_PATH.to_string());
+    if let Ok(term) = std::env::var("TERM") {
+        environment.insert("TERM".to_string(), term);
+    }
+    environment.insert("HOME".to_string(), credential.home.clone());
+    environment.insert("SHELL".to_string(), executable.to_string());
+    environment.insert("USER".to_string(), credential.name.to_string_lossy().to_string());
+    environment.insert("LOGNAME".to_string(), credential.name.to_string_lossy().to_string());
+    for e in envs {
+        if let Some((k, v)) = e.split_once('=') {
+            environment.insert(k.to_string(), v.to_string());
+        }
+    }
+    let environment: Vec<CString> = environment
+        .iter()
+        .map(|(k, v)| CString::new(format!("{}={}", k, v)).unwrap())
+        .collect();
+
+    if let Err(e) = nix::unistd::chdir(credential.home.as_str()) {
+        log::warn!("failed to change directory to {}: {}", credential.home, e);
+    }
+
+    // a login shell without arguments, like machinectl-shell does
+    let mut expanded_args: Vec<CString> = Vec::new();
+    if args.is_empty() {
+        expanded_args.push(CString::new(format!("-{}", shell)).unwrap());
+    } else {
+        expanded_args.push(CString::new(shell).unwrap());
+        for v in args {
+            expanded_args.push(CString::new(v.as_str()).unwrap());
+        }
+    }
+
+    log::trace!("executing shell: {} {:?}", executable, expanded_args);
+    nix::unistd::execve(&CString::new(executable).unwrap(), &expanded_args, &environment)?;
+    unreachable!();
+}
+
+/// Open a PAM session inside the bottle, run the shell, and close the
+/// session after the shell exits. Returns the exit status of the shell.
+fn run_pam_session(config: &Config, credential: &Credential, executable: &str, shell: &str, args: &[String]) -> Result<i32, ShellError> {
+    use nix::unistd::ForkResult;
+
+    let pw_name = credential.name.to_str().unwrap();
+    let mut pam = Pam::start(&config.shell.pam_service, pw_name)?;
+    pam.set_item(pam::PAM_RUSER, pw_name)?;
+    if let Ok(tty) = nix::unistd::ttyname(libc::STDIN_FILENO) {
+        pam.set_item(pam::PAM_TTY, tty.to_str().unwrap())?;
+        pam.putenv("XDG_SESSION_TYPE=tty")?;
+    }
+    pam.acct_mgmt()?;
+
+    log::trace!("initializing groups of {}", pw_name);
+    nix::unistd::initgroups(&credential.name, credential.gid)?;
+    pam.establish_credential()?;
+    pam.open_session()?;
+
+    // the runtime directory is created by logind when the session is opened
+    if let Err(e) = wslg::setup_user(config, credential.uid) {
+        log::warn!("failed to set up WSLg sockets: {}", e);
+    }
+
+    let mut envs = env::get_preserved_env(config);
+    envs.extend(pam.getenvlist());
+
+    match unsafe { nix::unistd::fork() }? {
+        ForkResult::Parent { child } => Ok(exec::wait_command(child)?),
+        ForkResult::Child => {
+            if let Err(e) = exec_pam_shell(credential, executable, shell, args, &envs) {
+                log::error!("{}", e);
+            }
+            std::process::exit(libc::EXIT_FAILURE);
+        }
+    }
+}
+
+fn launch_pam_session(config: &Config, pw_name: &str, shell: &str, args: &[String]) -> Result<(), ShellError> {
+    use nix::unistd::ForkResult;
+
+    let credential = exec::get_credential(pw_name)?;
+    let executable = get_shell_path(shell)?;
+
+    log::trace!("associating with bottled systemd");
+    systemd::associate_with_systemd(config)?;
+
+    // joining a PID namespace only takes effect on children, and logind
+    // requires the session to be opened from inside of it
+    match unsafe { nix::unistd::fork() }? {
+        ForkResult::Parent { child } => std::process::exit(exec::wait_command(child)?),
+        ForkResult::Child => match run_pam_session(config, &credential, &executable, shell, args) {
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+        },
+    }
 }
 
-pub fn launch_login_shell(bottled_shell_path: &String, shell: &String, args: &Vec<String>) -> Result<(), ShellError> {
+pub fn launch_login_shell(config: &Config, bottled_shell_path: &str, shell: &str, args: &[String]) -> Result<(), ShellError> {
     use std::ffi::CString;
 
-    if systemd::is_associated_with_systemd() {
+    if systemd::is_associated_with_systemd(config) {
         log::trace!("already associated with bottled systemd");
 
         log::trace!("releasing privilege");
         nix::unistd::setegid(nix::unistd::getgid()).unwrap();
         nix::unistd::seteuid(nix::unistd::getuid()).unwrap();
 
-        let executable = get_shell_path(&shell)?;
+        let executable = get_shell_path(shell)?;
         let mut expanded_args: Vec<CString> = vec![
-            CString::new(shell.as_str()).unwrap(),
+            CString::new(shell).unwrap(),
         ];
         for v in args {
             expanded_args.push(CString::new(v.as_str()).unwrap());
@@ -48,35 +200,28 @@ pub fn launch_login_shell(bottled_shell_path: &String, shell: &String, args: &Ve
 
         log::trace!("executing shell: {} {:?}", shell, expanded_args);
         nix::unistd::execv(&CString::new(executable).unwrap(), &expanded_args)?;
-    } else {
-        let uid = libc::uid_t::from(nix::unistd::getuid());
-        let pwent = unsafe { libc::getpwuid(uid) };
-        let pw_name = unsafe { std::ffi::CStr::from_ptr((*pwent).pw_name) }.to_str().unwrap();
-        log::trace!("username acquired: {}(UID={})", pw_name, uid);
+        unreachable!();
+    }
 
-        log::trace!("associating with bottled systemd");
-        systemd::associate_with_systemd().unwrap();
+    let uid = libc::uid_t::from(nix::unistd::getuid());
+    let pwent = unsafe { libc::getpwuid(uid) };
+    let pw_name = unsafe { std::ffi::CStr::from_ptr((*pwent).pw_name) }.to_str().unwrap();
+    log::trace!("username acquired: {}(UID={})", pw_name, uid);
 
-        let executable = systemd::get_machinectl_bin().unwrap();
-        let mut expanded_args: Vec<CString> = vec![
-            CString::new("machinectl").unwrap(),
-            CString::new("shell").unwrap(),
-        ];
-        expanded_args.push(CString::new("-q").unwrap());
-        for e in env::get_preserved_env() {
-            expanded_args.push(CString::new("-E").unwrap());
-            expanded_args.push(CString::new(e).unwrap());
-        }
-        expanded_args.push(CString::new(format!("{}@.host", pw_name)).unwrap());
-        if !args.is_empty() {
-            expanded_args.push(CString::new(bottled_shell_path.as_str()).unwrap());
-            for v in args {
-                expanded_args.push(CString::new(v.as_str()).unwrap());
+    let session = match config.shell.session {
+        SessionType::Auto => match systemd::get_machinectl_bin(config) {
+            Ok(_) => SessionType::Machinectl,
+            Err(_) => {
+                log::debug!("machinectl not found, falling back to PAM session");
+                SessionType::Pam
             }
-        }
+        },
+        s => s,
+    };
+    log::trace!("session type: {:?}", session);
 
-        log::trace!("launch session: {} {:?}", executable, expanded_args);
-        nix::unistd::execv(&CString::new(executable).unwrap(), &expanded_args)?;
+    match session {
+        SessionType::Pam => launch_pam_session(config, pw_name, shell, args),
+        _ => launch_machinectl_session(config, pw_name, bottled_shell_path, args),
     }
-    unreachable!();
 }
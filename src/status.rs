@@ -0,0 +1,251 @@
+use serde::Serialize;
+
+use crate::cgroup::{self, CgroupLimit};
+use crate::config::Config;
+use crate::guard::{self, GuardStatus};
+use crate::manager::SystemdManager;
+use crate::systemd::{self, SystemdError};
+
+/// State of the bottle, as seen from outside or inside of it.
+///
+/// Every state has its own exit code, see [`BottleState::exit_code`].
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BottleState {
+    /// systemd finished booting, and no unit failed.
+    Running,
+    /// systemd finished booting, but some units failed.
+    Degraded,
+    /// systemd is still booting.
+    Starting,
+    /// systemd is in rescue or emergency mode.
+    Maintenance,
+    /// systemd is shutting down.
+    Stopping,
+    /// No bottled systemd.
+    Stopped,
+    /// The PID file does not identify bottled systemd, e.g. after a crash.
+    Stale,
+    /// systemd is running, but its state can not be determined.
+    Unknown,
+}
+
+impl BottleState {
+    /// Map the `SystemState` reported by systemd to a state.
+    pub fn from_system_state(state: &str) -> BottleState {
+        match state {
+            "running" => BottleState::Running,
+            "degraded" => BottleState::Degraded,
+            "initializing" | "starting" => BottleState::Starting,
+            "maintenance" => BottleState::Maintenance,
+            "stopping" => BottleState::Stopping,
+            _ => BottleState::Unknown,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BottleState::Running => 0,
+            BottleState::Degraded => 1,
+            BottleState::Starting => 2,
+            BottleState::Maintenance => 3,
+            BottleState::Stopping => 4,
+            BottleState::Stopped => 5,
+            BottleState::Stale => 6,
+            BottleState::Unknown => 7,
+        }
+    }
+}
+
+impl std::fmt::Display for BottleState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            BottleState::Running => "running",
+            BottleState::Degraded => "degraded",
+            BottleState::Starting => "starting",
+            BottleState::Maintenance => "maintenance",
+            BottleState::Stopping => "stopping",
+            BottleState::Stopped => "stopped",
+            BottleState::Stale => "stale",
+            BottleState::Unknown => "unknown",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BottleStatus {
+    pub state: BottleState,
+    /// Whether the caller is inside the bottle.
+    pub inside: bool,
+    /// PID of systemd, as seen by the caller.
+    pub pid: Option<libc::pid_t>,
+    /// Start time of systemd, in seconds since the Unix epoch.
+    pub started_at: Option<u64>,
+    pub pid_namespace: Option<u64>,
+    pub mnt_namespace: Option<u64>,
+    /// Hostname seen inside the bottle.
+    pub hostname: Option<String>,
+    /// `SystemState` reported by systemd.
+    pub system_state: Option<String>,
+    pub failed_units: Option<u64>,
+    /// cgroup of the bottle, as seen by the caller.
+    pub cgroup: Option<String>,
+    /// Limits configured for the cgroup, and the current usage.
+    pub limits: Vec<CgroupLimit>,
+    /// Files generated by WSL guarded inside the bottle.
+    pub guarded: Vec<GuardStatus>,
+    /// Whether the PID file exists, but does not identify bottled systemd.
+    pub stale_pid_file: bool,
+}
+
+fn get_boot_time() -> Result<u64, SystemdError> {
+    let stat = std::fs::read_to_string("/proc/stat")?;
+    for line in stat.lines() {
+        if let Some(t) = line.strip_prefix("btime ") {
+            return Ok(t.trim().parse()?);
+        }
+    }
+    Err(SystemdError::IOError(std::io::Error::from(std::io::ErrorKind::InvalidData)))
+}
+
+fn get_started_at(pid: libc::pid_t) -> Result<u64, SystemdError> {
+    use nix::unistd::SysconfVar;
+
+    let ticks = systemd::get_proc_start_time(pid)?;
+    let ticks_per_second = nix::unistd::sysconf(SysconfVar::CLK_TCK)?.unwrap_or(100) as u64;
+    Ok(get_boot_time()? + ticks / ticks_per_second)
+}
+
+pub fn get_status(config: &Config) -> Result<BottleStatus, SystemdError> {
+    let inside = systemd::is_associated_with_systemd(config);
+    let pid = if inside { Some(1) } else { systemd::get_systemd_pid(config)? };
+    let stale_pid_file = !inside && systemd::is_pid_file_stale(config)?;
+
+    let mut status = BottleStatus {
+        state: if stale_pid_file { BottleState::Stale } else { BottleState::Stopped },
+        inside,
+        pid,
+        started_at: None,
+        pid_namespace: None,
+        mnt_namespace: None,
+        hostname: None,
+        system_state: None,
+        failed_units: None,
+        cgroup: None,
+        limits: Vec::new(),
+        guarded: Vec::new(),
+        stale_pid_file,
+    };
+
+    let pid = match pid {
+        Some(p) => p,
+        None => return Ok(status),
+    };
+    status.started_at = get_started_at(pid).ok();
+    status.pid_namespace = systemd::get_namespace_id(pid, "pid").ok();
+    status.mnt_namespace = systemd::get_namespace_id(pid, "mnt").ok();
+    status.hostname = systemd::get_hostname(pid)
+        .map_err(|e| log::debug!("failed to get hostname: {}", e))
+        .ok();
+
+    status.cgroup = cgroup::get_bottle_cgroup(config);
+    if let Some(path) = &status.cgroup {
+        status.limits = cgroup::get_limits(config, path);
+    }
+    status.guarded = guard::get_guard_status(config, pid)
+        .map_err(|e| log::debug!("failed to check guarded files: {}", e))
+        .unwrap_or_default();
+
+    match SystemdManager::connect(config, Some(pid)) {
+        Ok(mut m) => {
+            status.system_state = m.system_state().ok();
+            status.failed_units = m.list_units().ok()
+                .map(|units| units.iter().filter(|u| u.active_state == "failed").count() as u64);
+        }
+        Err(e) => log::debug!("failed to connect systemd: {}", e),
+    }
+    status.state = match &status.system_state {
+        Some(s) => BottleState::from_system_state(s),
+        None => BottleState::Unknown,
+    };
+    Ok(status)
+}
+
+fn format_time(t: u64) -> String {
+    // civil date from days since the Unix epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (t / 86400) as i64 + 719468;
+    let seconds = t % 86400;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, seconds / 3600, seconds % 3600 / 60, seconds % 60
+    )
+}
+
+impl std::fmt::Display for BottleStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "state: {}", self.state)?;
+        writeln!(f, "inside: {}", self.inside)?;
+        if let Some(pid) = self.pid {
+            writeln!(f, "pid: {}", pid)?;
+        }
+        if let Some(t) = self.started_at {
+            writeln!(f, "started at: {}", format_time(t))?;
+        }
+        if let Some(ns) = self.pid_namespace {
+            writeln!(f, "pid namespace: pid:[{}]", ns)?;
+        }
+        if let Some(ns) = self.mnt_namespace {
+            writeln!(f, "mnt namespace: mnt:[{}]", ns)?;
+        }
+        if let Some(h) = &self.hostname {
+            writeln!(f, "hostname: {}", h)?;
+        }
+        if let Some(s) = &self.system_state {
+            writeln!(f, "system state: {}", s)?;
+        }
+        if let Some(n) = self.failed_units {
+            writeln!(f, "failed units: {}", n)?;
+        }
+        if let Some(c) = &self.cgroup {
+            writeln!(f, "cgroup: {}", c)?;
+        }
+        for l in &self.limits {
+            match &l.usage {
+                Some(u) => writeln!(f, "{}: {} (usage: {})", l.name, l.value, u)?,
+                None => writeln!(f, "{}: {}", l.name, l.value)?,
+            }
+        }
+        for g in &self.guarded {
+            writeln!(f, "guard {}: {}", g.path, g.state)?;
+        }
+        write!(f, "stale pid file: {}", self.stale_pid_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00 UTC");
+        // leap days, 2100 is not a leap year
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_time(1709164800), "2024-02-29 00:00:00 UTC");
+        assert_eq!(format_time(4107542399), "2100-02-28 23:59:59 UTC");
+        assert_eq!(format_time(4107542400), "2100-03-01 00:00:00 UTC");
+        // year boundary
+        assert_eq!(format_time(1704067199), "2023-12-31 23:59:59 UTC");
+        assert_eq!(format_time(1704067200), "2024-01-01 00:00:00 UTC");
+    }
+}
@@ -0,0 +1,255 @@
+//! Supervises the children forked to start systemd.
+//!
+//! The session group leader reports failures through the readiness pipe,
+//! writes the PID file and exits, while systemd closes the pipe once it is
+//! executed. Readiness is decided by `ReadinessTracker` from events only, so
+//! it does not depend on forking or timing.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use nix::sys::wait::WaitStatus;
+use crate::pidfd::PidFd;
+
+/// Something observed while starting systemd.
+#[derive(Debug)]
+pub enum Event {
+    /// Failures reported by the children through the readiness pipe.
+    Report(Vec<u8>),
+    /// Every child closed the readiness pipe.
+    PipeClosed,
+    /// The session group leader was reaped.
+    LeaderExited(WaitStatus),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Readiness {
+    Pending,
+    /// systemd is executed, and the PID file is written.
+    Ready,
+    Failed(String),
+    TimedOut,
+}
+
+#[derive(Debug, Default)]
+pub struct ReadinessTracker {
+    report: Vec<u8>,
+    pipe_closed: bool,
+    leader: Option<WaitStatus>,
+}
+
+impl ReadinessTracker {
+    pub fn handle(&mut self, event: Event) -> Readiness {
+        match event {
+            Event::Report(r) => self.report.extend_from_slice(&r),
+            Event::PipeClosed => self.pipe_closed = true,
+            Event::LeaderExited(s) => self.leader = Some(s),
+        }
+        self.readiness()
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        // reports may still arrive until every child closed the pipe
+        if !self.pipe_closed {
+            return Readiness::Pending;
+        }
+        if !self.report.is_empty() {
+            let message = String::from_utf8_lossy(&self.report).trim().replace('\n', ", ");
+            return Readiness::Failed(message);
+        }
+        match self.leader {
+            Some(WaitStatus::Exited(_, 0)) => Readiness::Ready,
+            Some(WaitStatus::Exited(_, code)) => {
+                Readiness::Failed(format!("session group leader exited with {}", code))
+            }
+            Some(WaitStatus::Signaled(_, signal, _)) => {
+                Readiness::Failed(format!("session group leader killed by {}", signal))
+            }
+            _ => Readiness::Pending,
+        }
+    }
+}
+
+/// Wait for the children forked to start systemd, until they are ready, fail,
+/// or `timeout` elapsed. The session group leader is always reaped, and is
+/// killed on timeout.
+pub fn supervise(leader: nix::unistd::Pid, rfd: RawFd, timeout: std::time::Duration) -> nix::Result<Readiness> {
+    use nix::poll::{PollFd, PollFlags};
+
+    let leader_fd = PidFd::open(libc::pid_t::from(leader))?;
+    let mut tracker = ReadinessTracker::default();
+    let mut pipe_open = true;
+    let mut leader_running = true;
+
+    let start = std::time::Instant::now();
+    loop {
+        let remaining = timeout.saturating_sub(start.elapsed());
+        if remaining.is_zero() {
+            if leader_running {
+                log::debug!("killing session group leader(PID={})", leader);
+                leader_fd.send_signal(libc::SIGKILL)?;
+                reap(leader)?;
+            }
+            return Ok(Readiness::TimedOut);
+        }
+
+        let mut fds = Vec::new();
+        if pipe_open {
+            fds.push(PollFd::new(rfd, PollFlags::POLLIN));
+        }
+        if leader_running {
+            fds.push(PollFd::new(leader_fd.as_raw_fd(), PollFlags::POLLIN));
+        }
+        match nix::poll::poll(&mut fds, remaining.as_millis() as libc::c_int) {
+            Ok(0) | Err(nix::errno::Errno::EINTR) => continue,
+            Ok(_) => (),
+            Err(e) => return Err(e),
+        }
+        let mut revents = fds.iter().map(|f| f.revents().is_some_and(|r| !r.is_empty()));
+
+        let mut events = Vec::new();
+        if pipe_open && revents.next() == Some(true) {
+            let mut buffer = [0u8; 512];
+            match nix::unistd::read(rfd, &mut buffer) {
+                Ok(0) => {
+                    log::trace!("readiness pipe closed");
+                    pipe_open = false;
+                    events.push(Event::PipeClosed);
+                }
+                Ok(n) => events.push(Event::Report(buffer[..n].to_vec())),
+                Err(nix::errno::Errno::EINTR) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        if leader_running && revents.next() == Some(true) {
+            leader_running = false;
+            events.push(Event::LeaderExited(reap(leader)?));
+        }
+
+        for e in events {
+            let readiness = tracker.handle(e);
+            if readiness != Readiness::Pending {
+                // the leader exits right after closing the pipe
+                if leader_running {
+                    reap(leader)?;
+                }
+                return Ok(readiness);
+            }
+        }
+    }
+}
+
+fn reap(child: nix::unistd::Pid) -> nix::Result<WaitStatus> {
+    loop {
+        match nix::sys::wait::waitpid(child, None) {
+            Ok(status) => {
+                log::trace!("session group leader(PID={}): {:?}", child, status);
+                return Ok(status);
+            }
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::Signal;
+    use nix::unistd::Pid;
+
+    fn leader() -> Pid {
+        Pid::from_raw(42)
+    }
+
+    #[test]
+    fn test_ready() {
+        let mut tracker = ReadinessTracker::default();
+        assert_eq!(tracker.handle(Event::LeaderExited(WaitStatus::Exited(leader(), 0))), Readiness::Pending);
+        assert_eq!(tracker.handle(Event::PipeClosed), Readiness::Ready);
+    }
+
+    #[test]
+    fn test_ready_pipe_closed_first() {
+        let mut tracker = ReadinessTracker::default();
+        assert_eq!(tracker.handle(Event::PipeClosed), Readiness::Pending);
+        assert_eq!(tracker.handle(Event::LeaderExited(WaitStatus::Exited(leader(), 0))), Readiness::Ready);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut tracker = ReadinessTracker::default();
+        // reports may come in pieces, and are only final once the pipe is closed
+        assert_eq!(tracker.handle(Event::Report(b"mount #1 (bind /a on /b) fai".to_vec())), Readiness::Pending);
+        assert_eq!(tracker.handle(Event::Report(b"led: ENOENT\n".to_vec())), Readiness::Pending);
+        assert_eq!(tracker.handle(Event::LeaderExited(WaitStatus::Exited(leader(), 0))), Readiness::Pending);
+        assert_eq!(
+            tracker.handle(Event::PipeClosed),
+            Readiness::Failed("mount #1 (bind /a on /b) failed: ENOENT".to_string())
+        );
+    }
+
+    #[test]
+    fn test_reports_joined() {
+        let mut tracker = ReadinessTracker::default();
+        tracker.handle(Event::Report(b"setsid failed: EPERM\n".to_vec()));
+        tracker.handle(Event::Report(b"writing PID file failed: EACCES\n".to_vec()));
+        assert_eq!(
+            tracker.handle(Event::PipeClosed),
+            Readiness::Failed("setsid failed: EPERM, writing PID file failed: EACCES".to_string())
+        );
+    }
+
+    #[test]
+    fn test_leader_failed() {
+        let mut tracker = ReadinessTracker::default();
+        tracker.handle(Event::PipeClosed);
+        assert_eq!(
+            tracker.handle(Event::LeaderExited(WaitStatus::Exited(leader(), 1))),
+            Readiness::Failed("session group leader exited with 1".to_string())
+        );
+
+        let mut tracker = ReadinessTracker::default();
+        tracker.handle(Event::LeaderExited(WaitStatus::Signaled(leader(), Signal::SIGKILL, false)));
+        assert_eq!(
+            tracker.handle(Event::PipeClosed),
+            Readiness::Failed("session group leader killed by SIGKILL".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pending_until_leader_exited() {
+        let mut tracker = ReadinessTracker::default();
+        assert_eq!(tracker.handle(Event::PipeClosed), Readiness::Pending);
+        assert_eq!(tracker.readiness(), Readiness::Pending);
+    }
+
+    #[test]
+    fn test_supervise() {
+        use nix::fcntl::OFlag;
+
+        // a leader reporting nothing and exiting closes the pipe
+        let (rfd, wfd) = nix::unistd::pipe2(OFlag::O_CLOEXEC).unwrap();
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        nix::unistd::close(wfd).unwrap();
+        let readiness = supervise(Pid::from_raw(child.id() as libc::pid_t), rfd, std::time::Duration::from_secs(10));
+        nix::unistd::close(rfd).unwrap();
+        assert_eq!(readiness.unwrap(), Readiness::Ready);
+        // the leader was reaped
+        assert!(child.wait().is_err());
+    }
+
+    #[test]
+    fn test_supervise_timeout() {
+        use nix::fcntl::OFlag;
+
+        let (rfd, wfd) = nix::unistd::pipe2(OFlag::O_CLOEXEC).unwrap();
+        let mut child = std::process::Command::new("sleep").arg("60").spawn().unwrap();
+        let start = std::time::Instant::now();
+        let readiness = supervise(Pid::from_raw(child.id() as libc::pid_t), rfd, std::time::Duration::from_millis(200));
+        nix::unistd::close(rfd).unwrap();
+        nix::unistd::close(wfd).unwrap();
+        assert_eq!(readiness.unwrap(), Readiness::TimedOut);
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        // the leader was killed and reaped
+        assert!(child.wait().is_err());
+    }
+}
@@ -1,7 +1,30 @@
+use crate::binfmt;
+use crate::cgroup::{self, CgroupError, CgroupLayout};
+use crate::config::Config;
+use crate::dbus::DBusError;
 use crate::env;
+use crate::generator::{self, GeneratorError};
+use crate::guard::{self, GuardError};
+use crate::manager::SystemdManager;
+use crate::mount::{self, MountError};
+use crate::pidfd::PidFd;
+use crate::supervisor::{self, Readiness};
+use crate::units::{self, UnitError};
+use nix::sched::CloneFlags;
 
-static RUN_DIR: &str = "/run/bottled-shell";
-static PID_FILE: &str = "/run/bottled-shell/systemd.pid";
+/// Namespaces a bottle may have, in the order they are joined.
+static NAMESPACES: [(&str, CloneFlags); 5] = [
+    ("pid", CloneFlags::CLONE_NEWPID),
+    ("uts", CloneFlags::CLONE_NEWUTS),
+    ("ipc", CloneFlags::CLONE_NEWIPC),
+    ("cgroup", CloneFlags::CLONE_NEWCGROUP),
+    ("mnt", CloneFlags::CLONE_NEWNS),
+];
+
+static ENV_DROPINS: [(&str, &str); 2] = [
+    ("/run/systemd/system.conf.d", "10-bottled-shell-env.conf"),
+    ("/run/systemd/user.conf.d", "10-bottled-shell-env.conf"),
+];
 
 #[derive(thiserror::Error, Debug)]
 pub enum SystemdError {
@@ -11,9 +34,39 @@ pub enum SystemdError {
     #[error("systemd not running")]
     SystemdNotRunning,
 
+    #[error("failed to start systemd, {0}")]
+    StartFailed(String),
+
+    #[error("systemd not booted in time, still waiting for {0}")]
+    BootTimeout(String),
+
+    #[error("systemd(PID={0}) still running after SIGKILL")]
+    SystemdStillRunning(libc::pid_t),
+
+    #[error("not supported inside the namespace")]
+    InsideNamespace,
+
     #[error("no enough permission, required seteuid")]
     NoEnoughPermission,
 
+    #[error(transparent)]
+    CgroupError(#[from] CgroupError),
+
+    #[error(transparent)]
+    MountError(#[from] MountError),
+
+    #[error(transparent)]
+    UnitError(#[from] UnitError),
+
+    #[error(transparent)]
+    GeneratorError(#[from] GeneratorError),
+
+    #[error(transparent)]
+    GuardError(#[from] GuardError),
+
+    #[error(transparent)]
+    DBusError(#[from] DBusError),
+
     #[error(transparent)]
     ParseIntError(#[from] std::num::ParseIntError),
 
@@ -35,11 +88,11 @@ fn check_permission() -> Result<bool, SystemdError> {
     Err(SystemdError::NoEnoughPermission)
 }
 
-fn check_systemd_proc(pid: libc::pid_t) -> bool {
+fn check_systemd_proc(config: &Config, pid: libc::pid_t) -> bool {
     let path = std::format!("/proc/{}/cmdline", pid);
     if let Ok(buffer) = std::fs::read(path.clone()) {
         let cmdline = String::from_utf8(buffer).unwrap();
-        if cmdline.split('\0').next().unwrap() == get_systemd_bin().unwrap() {
+        if cmdline.split('\0').next().unwrap() == get_systemd_bin(config).unwrap() {
             log::trace!("check {}: match", path);
             return true;
         }
@@ -48,16 +101,12 @@ fn check_systemd_proc(pid: libc::pid_t) -> bool {
     false
 }
 
-pub fn is_associated_with_systemd() -> bool {
-    check_systemd_proc(1)
+pub fn is_associated_with_systemd(config: &Config) -> bool {
+    check_systemd_proc(config, 1)
 }
 
-fn get_systemd_bin() -> Result<String, SystemdError> {
-    let search_location = [
-        "/lib/systemd/systemd",
-        "/usr/lib/systemd/systemd",
-    ];
-    for l in search_location {
+fn get_systemd_bin(config: &Config) -> Result<String, SystemdError> {
+    for l in &config.systemd.search_path {
         if std::fs::metadata(l).is_ok() {
             return Ok(l.to_string());
         }
@@ -65,12 +114,8 @@ fn get_systemd_bin() -> Result<String, SystemdError> {
     Err(SystemdError::SystemdNotFound)
 }
 
-pub fn get_machinectl_bin() -> Result<String, SystemdError> {
-    let search_location = [
-        "/usr/bin/machinectl",
-        "/bin/machinectl",
-    ];
-    for l in search_location {
+pub fn get_machinectl_bin(config: &Config) -> Result<String, SystemdError> {
+    for l in &config.systemd.machinectl_search_path {
         if std::fs::metadata(l).is_ok() {
             return Ok(l.to_string());
         }
@@ -78,20 +123,117 @@ pub fn get_machinectl_bin() -> Result<String, SystemdError> {
     Err(SystemdError::SystemdNotFound)
 }
 
-pub fn get_systemd_pid() -> Result<Option<libc::pid_t>, SystemdError> {
-    let buffer = std::fs::read(PID_FILE);
+/// Identity of bottled systemd, recorded in the PID file.
+///
+/// The PID alone may be reused after a crash, so the start time and the
+/// namespaces of the process are recorded and verified as well. The first
+/// line of the PID file is still the bare PID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemdIdentity {
+    pub pid: libc::pid_t,
+    /// Start time, in clock ticks since boot.
+    pub start_time: Option<u64>,
+    pub pid_namespace: Option<u64>,
+    pub mnt_namespace: Option<u64>,
+}
+
+impl SystemdIdentity {
+    fn of_process(pid: libc::pid_t) -> Result<SystemdIdentity, SystemdError> {
+        Ok(SystemdIdentity {
+            pid,
+            start_time: Some(get_proc_start_time(pid)?),
+            pid_namespace: Some(get_namespace_id(pid, "pid")?),
+            mnt_namespace: Some(get_namespace_id(pid, "mnt")?),
+        })
+    }
+
+    fn parse(content: &str) -> Result<SystemdIdentity, SystemdError> {
+        let mut lines = content.lines();
+        let mut identity = SystemdIdentity {
+            pid: lines.next().unwrap_or_default().trim().parse()?,
+            start_time: None,
+            pid_namespace: None,
+            mnt_namespace: None,
+        };
+        for line in lines {
+            match line.split_once('=') {
+                Some(("start-time", v)) => identity.start_time = Some(v.trim().parse()?),
+                Some(("pid-namespace", v)) => identity.pid_namespace = Some(v.trim().parse()?),
+                Some(("mnt-namespace", v)) => identity.mnt_namespace = Some(v.trim().parse()?),
+                _ => log::debug!("unknown line in PID file: {}", line),
+            }
+        }
+        Ok(identity)
+    }
+
+    fn format(&self) -> String {
+        let mut content = format!("{}\n", self.pid);
+        if let Some(v) = self.start_time {
+            content.push_str(&format!("start-time={}\n", v));
+        }
+        if let Some(v) = self.pid_namespace {
+            content.push_str(&format!("pid-namespace={}\n", v));
+        }
+        if let Some(v) = self.mnt_namespace {
+            content.push_str(&format!("mnt-namespace={}\n", v));
+        }
+        content
+    }
+
+    /// Whether the recorded process is still bottled systemd.
+    pub fn verify(&self, config: &Config) -> bool {
+        if !check_systemd_proc(config, self.pid) {
+            return false;
+        }
+        let actual = match SystemdIdentity::of_process(self.pid) {
+            Ok(i) => i,
+            Err(e) => {
+                log::trace!("check PID={}: {}", self.pid, e);
+                return false;
+            }
+        };
+        // PID files written by older versions only have the PID
+        let matches = |recorded: Option<u64>, actual: Option<u64>, name: &str| {
+            if recorded.is_some() && recorded != actual {
+                log::trace!("check PID={}: {} mismatch, {:?} != {:?}", self.pid, name, recorded, actual);
+                return false;
+            }
+            true
+        };
+        matches(self.start_time, actual.start_time, "start time")
+            && matches(self.pid_namespace, actual.pid_namespace, "PID namespace")
+            && matches(self.mnt_namespace, actual.mnt_namespace, "MNT namespace")
+    }
+}
+
+/// Whether the PID file exists, stale or not.
+fn has_pid_file(config: &Config) -> bool {
+    std::fs::symlink_metadata(&config.bottle.pid_file).is_ok()
+}
+
+/// Read the identity recorded in the PID file, without checking the process.
+/// `None` when the PID file is missing, or unparsable and thus stale.
+pub fn read_systemd_identity(config: &Config) -> Result<Option<SystemdIdentity>, SystemdError> {
+    let pid_file = &config.bottle.pid_file;
+    let buffer = std::fs::read(pid_file);
     match buffer {
         Ok(b) => {
-            let pid = String::from_utf8(b)?.trim().parse()?;
-            log::trace!("check {}: PID={}", PID_FILE, pid);
-            if check_systemd_proc(pid) {
-                return Ok(Some(pid));
-            }
-            Ok(None)
+            let parsed = String::from_utf8(b)
+                .map_err(SystemdError::from)
+                .and_then(|c| SystemdIdentity::parse(&c));
+            let identity = match parsed {
+                Ok(i) => i,
+                Err(e) => {
+                    log::debug!("stale PID file, {} unparsable: {}", pid_file, e);
+                    return Ok(None);
+                }
+            };
+            log::trace!("check {}: {:?}", pid_file, identity);
+            Ok(Some(identity))
         }
         Err(e) => {
             if e.kind() == std::io::ErrorKind::NotFound {
-                log::trace!("check {}: missing", PID_FILE);
+                log::trace!("check {}: missing", pid_file);
                 Ok(None)
             } else {
                 Err(SystemdError::IOError(e))
@@ -100,100 +242,533 @@ pub fn get_systemd_pid() -> Result<Option<libc::pid_t>, SystemdError> {
     }
 }
 
-fn put_systemd_pid(pid: libc::pid_t) -> std::io::Result<()> {
-    std::fs::create_dir_all(RUN_DIR)?;
-    std::fs::write(PID_FILE, format!("{}\n", pid))
+/// Open bottled systemd recorded in the PID file, `None` when it is not
+/// running, or when the PID file is stale.
+///
+/// The process is verified after the pidfd is opened, so the pidfd refers
+/// to bottled systemd even if the PID is reused later.
+fn open_systemd(config: &Config) -> Result<Option<(SystemdIdentity, PidFd)>, SystemdError> {
+    let identity = match read_systemd_identity(config)? {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    let pidfd = match PidFd::open(identity.pid) {
+        Ok(p) => p,
+        Err(nix::errno::Errno::ESRCH) => {
+            log::debug!("stale PID file, PID={} exited", identity.pid);
+            return Ok(None);
+        }
+        Err(e) => return Err(SystemdError::NixErrno(e)),
+    };
+    if !identity.verify(config) || !pidfd.is_alive() {
+        log::debug!("stale PID file, PID={}", identity.pid);
+        return Ok(None);
+    }
+    Ok(Some((identity, pidfd)))
+}
+
+/// pidfd of bottled systemd, `None` when it is not running, or when the PID
+/// file is stale. Long-lived processes should hold it instead of the PID.
+pub fn get_systemd_pidfd(config: &Config) -> Result<Option<PidFd>, SystemdError> {
+    Ok(open_systemd(config)?.map(|(_, pidfd)| pidfd))
+}
+
+/// PID of bottled systemd, `None` when it is not running, or when the PID
+/// file is stale.
+pub fn get_systemd_pid(config: &Config) -> Result<Option<libc::pid_t>, SystemdError> {
+    Ok(get_systemd_pidfd(config)?.map(|pidfd| pidfd.pid()))
+}
+
+/// Whether the PID file exists, but does not identify bottled systemd.
+pub fn is_pid_file_stale(config: &Config) -> Result<bool, SystemdError> {
+    Ok(has_pid_file(config) && open_systemd(config)?.is_none())
+}
+
+/// Start time of a process, in clock ticks since boot.
+pub fn get_proc_start_time(pid: libc::pid_t) -> Result<u64, SystemdError> {
+    parse_proc_start_time(&std::fs::read_to_string(format!("/proc/{}/stat", pid))?)
+}
+
+/// Start time from the content of `/proc/<pid>/stat`.
+fn parse_proc_start_time(stat: &str) -> Result<u64, SystemdError> {
+    // comm may contain spaces and parentheses, fields are counted from the last ')'
+    let fields = match stat.rsplit_once(')') {
+        Some((_, f)) => f,
+        None => return Err(SystemdError::IOError(std::io::Error::from(std::io::ErrorKind::InvalidData))),
+    };
+    match fields.split_whitespace().nth(19) {
+        Some(t) => Ok(t.parse()?),
+        None => Err(SystemdError::IOError(std::io::Error::from(std::io::ErrorKind::InvalidData))),
+    }
+}
+
+/// Inode number identifying a namespace of a process, e.g. `pid` or `mnt`.
+pub fn get_namespace_id(pid: libc::pid_t, ns: &str) -> Result<u64, SystemdError> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(std::fs::metadata(format!("/proc/{}/ns/{}", pid, ns))?.ino())
+}
+
+/// Namespaces created for the bottle, the PID and mount namespaces, and the
+/// ones enabled in `namespaces`.
+fn get_bottle_namespaces(config: &Config) -> Vec<(&'static str, CloneFlags)> {
+    NAMESPACES
+        .iter()
+        .filter(|(ns, _)| {
+            matches!(*ns, "pid" | "mnt") || config.bottle.namespaces.iter().any(|n| n.name() == *ns)
+        })
+        .copied()
+        .collect()
+}
+
+fn get_namespace_dir(config: &Config) -> String {
+    format!("{}/ns", config.bottle.run_dir)
+}
+
+/// Stable path of a namespace of bottled systemd, e.g. `pid` or `mnt`.
+pub fn get_namespace_path(config: &Config, ns: &str) -> String {
+    format!("{}/{}", get_namespace_dir(config), ns)
+}
+
+/// Paths of the namespace handles of the running bottled systemd.
+pub fn get_namespace_handles(config: &Config) -> Result<Vec<(&'static str, String)>, SystemdError> {
+    if is_associated_with_systemd(config) {
+        return Err(SystemdError::InsideNamespace);
+    }
+    if get_systemd_pid(config)?.is_none() {
+        return Err(SystemdError::SystemdNotRunning);
+    }
+    Ok(NAMESPACES
+        .iter()
+        .map(|(ns, _)| (*ns, get_namespace_path(config, ns)))
+        .filter(|(_, path)| std::fs::metadata(path).is_ok())
+        .collect())
+}
+
+/// Make the directory of namespace handles a private mount point.
+///
+/// A handle of the mount namespace must not propagate into the namespace
+/// itself, the kernel refuses such a bind mount.
+fn prepare_namespace_dir(config: &Config) -> Result<(), SystemdError> {
+    use nix::mount::MsFlags;
+
+    let dir = get_namespace_dir(config);
+    std::fs::create_dir_all(&dir)?;
+
+    let make_private = || nix::mount::mount(
+        None as Option<&str>,
+        dir.as_str(),
+        None as Option<&str>,
+        MsFlags::MS_PRIVATE,
+        None as Option<&str>,
+    );
+    // only mount points can change propagation
+    if make_private() == Err(nix::errno::Errno::EINVAL) {
+        log::trace!("bind mounting {} onto itself", dir);
+        nix::mount::mount(
+            Some(dir.as_str()),
+            dir.as_str(),
+            None as Option<&str>,
+            MsFlags::MS_BIND,
+            None as Option<&str>,
+        )?;
+        make_private()?;
+    }
+    Ok(())
+}
+
+/// Bind mount the namespaces of systemd(PID=`pid`) to their stable paths.
+fn bind_namespaces(config: &Config, pid: libc::pid_t) -> Result<(), SystemdError> {
+    use nix::mount::MsFlags;
+
+    for (ns, _) in get_bottle_namespaces(config) {
+        let source = format!("/proc/{}/ns/{}", pid, ns);
+        let target = get_namespace_path(config, ns);
+        release_namespace(&target)?;
+        std::fs::File::create(&target)?;
+
+        log::trace!("bind mounting {} to {}", source, target);
+        nix::mount::mount(
+            Some(source.as_str()),
+            target.as_str(),
+            None as Option<&str>,
+            MsFlags::MS_BIND,
+            None as Option<&str>,
+        )?;
+    }
+    Ok(())
 }
 
-fn updated_systemd_envs() -> std::io::Result<()> {
-    let envs = env::get_preserved_env().join(" ");
+/// Unmount and remove a namespace handle, the namespace is freed once no
+/// process is left inside.
+fn release_namespace(path: &str) -> Result<(), SystemdError> {
+    use nix::mount::MntFlags;
+
+    if std::fs::metadata(path).is_err() {
+        return Ok(());
+    }
+    match nix::mount::umount2(path, MntFlags::MNT_DETACH) {
+        Ok(_) => log::trace!("unmounted {}", path),
+        // not mounted
+        Err(nix::errno::Errno::EINVAL) => (),
+        Err(e) => return Err(SystemdError::NixErrno(e)),
+    }
+    log::trace!("removing {}", path);
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Hostname to set in the bottle, `None` when it shares the hostname of WSL.
+fn get_bottle_hostname(config: &Config) -> Result<Option<String>, SystemdError> {
+    let uts = get_bottle_namespaces(config).iter().any(|(ns, _)| *ns == "uts");
+    if !uts || config.bottle.hostname.is_empty() {
+        return Ok(None);
+    }
+    let mut buffer = [0u8; 256];
+    let host = nix::unistd::gethostname(&mut buffer)?.to_string_lossy().to_string();
+    Ok(Some(config.bottle.hostname.replace("%h", &host)))
+}
+
+fn get_hostname_file(config: &Config) -> String {
+    format!("{}/hostname", config.bottle.run_dir)
+}
+
+/// Hostname seen inside the bottle of systemd(PID=`pid`).
+pub fn get_hostname(pid: libc::pid_t) -> Result<String, SystemdError> {
+    use std::os::unix::io::AsRawFd;
+
+    let gethostname = || -> Result<String, SystemdError> {
+        let mut buffer = [0u8; 256];
+        Ok(nix::unistd::gethostname(&mut buffer)?.to_string_lossy().to_string())
+    };
+    if get_namespace_id(pid, "uts")? == get_namespace_id(nix::unistd::getpid().as_raw(), "uts")? {
+        return gethostname();
+    }
+
+    // the UTS namespace is per thread, join it without affecting the caller
+    let ns = std::fs::File::open(format!("/proc/{}/ns/uts", pid))?;
+    std::thread::spawn(move || {
+        nix::sched::setns(ns.as_raw_fd(), CloneFlags::CLONE_NEWUTS)?;
+        gethostname()
+    })
+    .join()
+    .unwrap_or_else(|_| Err(SystemdError::IOError(std::io::Error::from(std::io::ErrorKind::Other))))
+}
+
+fn put_systemd_pid(config: &Config, identity: &SystemdIdentity) -> Result<(), SystemdError> {
+    std::fs::create_dir_all(&config.bottle.run_dir)?;
+    // renamed once written, so that readers never see a partial PID file
+    let temp = format!("{}.tmp", config.bottle.pid_file);
+    std::fs::write(&temp, identity.format())?;
+    std::fs::rename(&temp, &config.bottle.pid_file)?;
+    Ok(())
+}
+
+fn updated_systemd_envs(config: &Config) -> std::io::Result<()> {
+    let envs = env::get_preserved_env(config).join(" ");
     let config = format!("[Manager]\nDefaultEnvironment={}\n", envs);
     log::trace!("updating systemd environment variables: {}", envs);
 
-    std::fs::create_dir_all("/run/systemd/system.conf.d")?;
-    std::fs::write("/run/systemd/system.conf.d/10-bottled-shell-env.conf", &config)?;
+    for (dir, file) in ENV_DROPINS {
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(format!("{}/{}", dir, file), &config)?;
+    }
 
-    std::fs::create_dir_all("/run/systemd/user.conf.d")?;
-    std::fs::write("/run/systemd/user.conf.d/10-bottled-shell-env.conf", &config)?;
+    Ok(())
+}
 
+/// Install `bottled generator` into the bottle, or remove it when disabled.
+fn updated_generator(config: &Config) -> Result<(), SystemdError> {
+    if !config.systemd.generator {
+        return Ok(generator::remove_generator()?);
+    }
+    let bottled = std::env::current_exe()?;
+    generator::install_generator(&bottled.to_string_lossy())?;
     Ok(())
 }
 
-pub fn start_systemd() -> Result<(), SystemdError> {
-    use nix::fcntl::OFlag;
-    use nix::poll::PollFlags;
-    use nix::sched::CloneFlags;
-    use nix::unistd::ForkResult;
+/// Remove the PID file, drop-ins, unit masks, generator and namespace handles,
+/// once bottled systemd is gone.
+fn cleanup_systemd_files(config: &Config) -> Result<(), SystemdError> {
+    for (ns, _) in NAMESPACES {
+        release_namespace(&get_namespace_path(config, ns))?;
+    }
+    if let Err(e) = cgroup::remove_bottle_cgroup(config) {
+        log::warn!("failed to remove the cgroup of the bottle: {}", e);
+    } else if let Err(e) = cgroup::release_cgroup2_mount(config) {
+        log::warn!("failed to unmount cgroup2: {}", e);
+    }
+    if let Err(e) = units::remove_unit_masks(config) {
+        log::warn!("failed to remove the unit masks: {}", e);
+    }
+    if let Err(e) = generator::remove_generator() {
+        log::warn!("failed to remove the generator: {}", e);
+    }
+    if let Err(e) = guard::remove_snapshots(config) {
+        log::warn!("failed to remove the snapshots of guarded files: {}", e);
+    }
 
-    if is_associated_with_systemd() {
+    let mut files = vec![config.bottle.pid_file.clone(), get_hostname_file(config)];
+    files.extend(ENV_DROPINS.iter().map(|(dir, file)| format!("{}/{}", dir, file)));
+    for f in files {
+        if std::fs::metadata(&f).is_ok() {
+            log::trace!("removing {}", f);
+            std::fs::remove_file(&f)?;
+        }
+    }
+    Ok(())
+}
+
+/// Advisory lock under the runtime directory, serializing start-up.
+/// Released when dropped.
+struct BottleLock {
+    fd: std::os::unix::io::RawFd,
+}
+
+impl BottleLock {
+    fn acquire(config: &Config) -> Result<BottleLock, SystemdError> {
+        use nix::fcntl::{FlockArg, OFlag};
+        use nix::sys::stat::Mode;
+
+        std::fs::create_dir_all(&config.bottle.run_dir)?;
+        let path = format!("{}/lock", config.bottle.run_dir);
+        let fd = nix::fcntl::open(
+            path.as_str(),
+            OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o600),
+        )?;
+        let lock = BottleLock { fd };
+
+        if let Err(e) = nix::fcntl::flock(fd, FlockArg::LockExclusiveNonblock) {
+            if e != nix::errno::Errno::EWOULDBLOCK {
+                return Err(SystemdError::NixErrno(e));
+            }
+            log::info!("waiting for systemd being started by another process");
+            nix::fcntl::flock(fd, FlockArg::LockExclusive)?;
+        }
+        log::trace!("acquired {}", path);
+        Ok(lock)
+    }
+}
+
+impl Drop for BottleLock {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.fd);
+    }
+}
+
+pub fn start_systemd(config: &Config) -> Result<(), SystemdError> {
+    if is_associated_with_systemd(config) {
         log::info!("systemd already started");
         return Ok(());
     }
 
-    if let Ok(Some(pid)) = get_systemd_pid() {
-        log::info!("systemd already started, PID={}", pid);
-        return Ok(());
+    if !ensure_systemd(config)? {
+        log::info!("systemd already started");
     }
+    Ok(())
+}
 
+/// Start systemd unless it is running, returns whether it was started.
+///
+/// A start in progress in another process is waited for, so systemd has
+/// booted once this returns, whoever started it.
+pub fn ensure_systemd(config: &Config) -> Result<bool, SystemdError> {
     check_permission()?;
 
-    let systemd_bin = std::ffi::CString::new(get_systemd_bin().unwrap()).unwrap();
+    let _lock = BottleLock::acquire(config)?;
+    start_systemd_locked(config)
+}
+
+fn start_systemd_locked(config: &Config) -> Result<bool, SystemdError> {
+    // the PID file is only written by starts holding the lock, and is removed
+    // when they fail, so systemd found here has booted
+    if let Ok(Some(pid)) = get_systemd_pid(config) {
+        log::debug!("systemd already started, PID={}", pid);
+        return Ok(false);
+    }
+
+    let systemd_bin = std::ffi::CString::new(get_systemd_bin(config)?).unwrap();
     log::trace!("systemd location = {}", systemd_bin.to_str().unwrap());
 
-    updated_systemd_envs().unwrap();
+    for m in &config.mounts {
+        mount::check_mount(m)?;
+    }
+    for u in units::get_masked_units(config) {
+        units::check_unit_name(&u)?;
+    }
 
-    let (rfd, wfd) = nix::unistd::pipe2(OFlag::O_CLOEXEC).unwrap();
-    match unsafe { nix::unistd::fork() } {
-        Ok(ForkResult::Parent { .. }) => {
-            nix::unistd::close(wfd).unwrap();
+    let result = spawn_systemd(config, systemd_bin);
+    if result.is_err() {
+        rollback_start(config);
+    }
+    result.map(|_| true)
+}
 
-            let start = std::time::Instant::now();
-            let timeout = std::time::Duration::from_secs(10);
-            let mut fds = [nix::poll::PollFd::new(rfd, PollFlags::POLLIN)];
-            loop {
-                let elapsed = start.elapsed();
-                if elapsed >= timeout {
-                    log::error!("systemd not started in time");
-                    return Err(SystemdError::SystemdNotRunning)
+/// Undo a failed start, kill the half-started systemd, and the children
+/// which never executed it, and remove the files written so far, so that
+/// the bottle is left stopped.
+fn rollback_start(config: &Config) {
+    log::warn!("rolling back the failed start");
+    match get_systemd_pidfd(config) {
+        Ok(Some(pidfd)) => {
+            log::debug!("killing half-started systemd(PID={})", pidfd.pid());
+            let timeout = std::time::Duration::from_secs(config.systemd.kill_timeout);
+            let killed = get_namespace_id(pidfd.pid(), "pid").and_then(|ns| {
+                match pidfd.send_signal(libc::SIGKILL) {
+                    // exited meanwhile
+                    Ok(_) | Err(nix::errno::Errno::ESRCH) => (),
+                    Err(e) => return Err(SystemdError::NixErrno(e)),
+                }
+                wait_for_exit(&pidfd, ns, timeout)
+            });
+            match killed {
+                Ok(true) => (),
+                Ok(false) => {
+                    log::error!("systemd(PID={}) still running after SIGKILL", pidfd.pid());
+                    return;
                 }
+                Err(e) => {
+                    log::error!("failed to kill systemd(PID={}): {}", pidfd.pid(), e);
+                    return;
+                }
+            }
+        }
+        Ok(None) => (),
+        Err(e) => log::debug!("failed to check systemd: {}", e),
+    }
+    // children which failed before executing systemd are not in the PID file
+    let timeout = std::time::Duration::from_secs(config.systemd.kill_timeout);
+    match cgroup::kill_bottle_cgroup(config, timeout) {
+        Ok(true) => (),
+        Ok(false) => {
+            log::error!("processes left in the cgroup of the bottle after SIGKILL");
+            return;
+        }
+        Err(e) => log::debug!("failed to kill the cgroup of the bottle: {}", e),
+    }
+    if let Err(e) = cleanup_systemd_files(config) {
+        log::error!("failed to clean up: {}", e);
+    }
+}
+
+fn spawn_systemd(config: &Config, systemd_bin: std::ffi::CString) -> Result<(), SystemdError> {
+    use nix::fcntl::OFlag;
+    use nix::unistd::ForkResult;
 
-                let remaining = timeout - elapsed;
-                if nix::poll::poll(&mut fds, remaining.as_millis() as libc::c_int)? > 0 {
-                    if let Some(ev) = fds[0].revents() {
-                        if ev.contains(PollFlags::POLLHUP) {
-                            if let Ok(Some(pid)) = get_systemd_pid() {
-                                log::info!("systemd(PID={}) started", pid);
+    updated_systemd_envs(config)?;
+    units::updated_unit_masks(config)?;
+    updated_generator(config)?;
+    prepare_namespace_dir(config)?;
 
-                                log::trace!("sending SIGRTMIN + 0 to systemd(PID={})", pid);
-                                unsafe { nix::libc::kill(pid, libc::SIGRTMIN() + 0); }
-                                std::thread::sleep(std::time::Duration::from_secs(1));
+    let cgroup = cgroup::prepare_bottle_cgroup(config)?;
+    log::trace!("cgroup of the bottle = {}", cgroup);
+    // /sys/fs/cgroup is already cgroup2 with the unified layout, but then
+    // shows the whole hierarchy rather than the one of the cgroup namespace
+    let mount_cgroup2 = cgroup::get_layout()? != CgroupLayout::Unified
+        || get_bottle_namespaces(config).iter().any(|(ns, _)| *ns == "cgroup");
 
-                                return Ok(());
-                            }
-                        }
-                    }
+    let hostname = get_bottle_hostname(config)?;
+    if let Some(h) = &hostname {
+        log::trace!("hostname of the bottle = {}", h);
+        std::fs::write(get_hostname_file(config), format!("{}\n", h))?;
+    }
+
+    let guarded = if config.guard.enable { guard::save_snapshots(config)? } else { Vec::new() };
+    log::trace!("guarded files = {:?}", guarded);
+
+    if config.interop.restore {
+        if let Err(e) = binfmt::record_interop(config) {
+            log::warn!("failed to record the interop registration: {}", e);
+        }
+    }
+
+    // children report failures through the pipe, and it is closed once
+    // systemd is executed
+    let (rfd, wfd) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+    match unsafe { nix::unistd::fork() } {
+        Ok(ForkResult::Parent { child }) => {
+            nix::unistd::close(wfd)?;
+
+            let timeout = std::time::Duration::from_secs(config.systemd.start_timeout);
+            let readiness = supervisor::supervise(child, rfd, timeout);
+            nix::unistd::close(rfd)?;
+
+            match readiness? {
+                Readiness::Ready => (),
+                Readiness::Failed(message) => return Err(SystemdError::StartFailed(message)),
+                _ => {
+                    log::error!("systemd not started in time");
+                    return Err(SystemdError::SystemdNotRunning);
                 }
             }
+
+            let pidfd = match get_systemd_pidfd(config)? {
+                Some(p) => p,
+                None => {
+                    log::error!("systemd exited right after started");
+                    return Err(SystemdError::SystemdNotRunning);
+                }
+            };
+            let pid = pidfd.pid();
+            // /proc of the session group leader may already be the one of
+            // the bottle, so the identity is completed from here
+            let identity = SystemdIdentity::of_process(pid)?;
+            if !pidfd.is_alive() {
+                log::error!("systemd exited right after started");
+                return Err(SystemdError::SystemdNotRunning);
+            }
+            log::trace!("updating PID file with {:?}", identity);
+            put_systemd_pid(config, &identity)?;
+            log::info!("systemd(PID={}) started", pid);
+            bind_namespaces(config, pid)?;
+
+            log::trace!("sending SIGRTMIN + 0 to systemd(PID={})", pid);
+            pidfd.send_signal(libc::SIGRTMIN())?;
+
+            wait_for_boot(config, &pidfd)?;
+            restore_interop(config);
+            Ok(())
         }
         Ok(ForkResult::Child) => {
-            nix::unistd::close(rfd).unwrap();
+            let _ = nix::unistd::close(rfd);
 
             log::trace!("updating UID & GID");
-            nix::unistd::setgid(nix::unistd::Gid::from_raw(0)).unwrap();
-            nix::unistd::setuid(nix::unistd::Uid::from_raw(0)).unwrap();
+            check_child_step(wfd, "setgid(0)", nix::unistd::setgid(nix::unistd::Gid::from_raw(0)));
+            check_child_step(wfd, "setuid(0)", nix::unistd::setuid(nix::unistd::Uid::from_raw(0)));
+
+            log::trace!("entering cgroup");
+            check_child_step(wfd, &format!("entering cgroup {}", cgroup), cgroup::enter_cgroup(&cgroup));
 
             log::trace!("creating new namespace");
-            nix::sched::unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID).unwrap();
+            let flags = get_bottle_namespaces(config)
+                .iter()
+                .fold(CloneFlags::empty(), |flags, (_, f)| flags | *f);
+            check_child_step(wfd, &format!("unshare({:?})", flags), nix::sched::unshare(flags));
 
-            log::trace!("creating new session group");
-            nix::unistd::setsid().unwrap();
+            if let Some(h) = &hostname {
+                log::trace!("setting hostname");
+                check_child_step(wfd, &format!("sethostname {}", h), nix::unistd::sethostname(h));
+            }
 
-            match unsafe { nix::unistd::fork() } {
-                Ok(ForkResult::Parent { child, .. }) => {
-                    nix::unistd::close(wfd).unwrap();
+            log::trace!("creating new session group");
+            check_child_step(wfd, "setsid", nix::unistd::setsid());
 
+            match check_child_step(wfd, "fork", unsafe { nix::unistd::fork() }) {
+                ForkResult::Parent { child, .. } => {
                     log::trace!("updating PID file with {}", child);
-                    put_systemd_pid(libc::pid_t::from(child)).unwrap();
+                    let identity = SystemdIdentity {
+                        pid: libc::pid_t::from(child),
+                        start_time: None,
+                        pid_namespace: None,
+                        mnt_namespace: None,
+                    };
+                    if let Err(e) = put_systemd_pid(config, &identity) {
+                        // nobody else knows about systemd without the PID file
+                        let _ = nix::sys::signal::kill(child, nix::sys::signal::Signal::SIGKILL);
+                        check_child_step(wfd, "writing PID file", Err::<(), _>(e));
+                    }
+                    let _ = nix::unistd::close(wfd);
 
                     log::trace!(
                         "session group leader(PID={}) terminated successfully",
@@ -201,131 +776,521 @@ pub fn start_systemd() -> Result<(), SystemdError> {
                     );
                     std::process::exit(libc::EXIT_SUCCESS);
                 }
-                Ok(ForkResult::Child) => {
-                    exec_systemd(systemd_bin);
-                    std::process::exit(libc::EXIT_FAILURE);
+                ForkResult::Child => {
+                    let hostname_file = hostname.map(|_| get_hostname_file(config));
+                    exec_systemd(config, systemd_bin, mount_cgroup2, hostname_file, &guarded, wfd);
                 }
-                Err(e) => Err(SystemdError::NixErrno(e))
             }
         }
         Err(e) => Err(SystemdError::NixErrno(e))
     }
 }
 
-fn exec_systemd(systemd_bin: std::ffi::CString) {
+/// Unwrap the result of a step in a forked child, or report the failure
+/// through the readiness pipe and exit.
+fn check_child_step<T, E: std::fmt::Display>(wfd: std::os::unix::io::RawFd, step: &str, result: Result<T, E>) -> T {
+    match result {
+        Ok(v) => v,
+        Err(e) => {
+            let message = format!("{} failed: {}\n", step, e);
+            let _ = nix::unistd::write(wfd, message.as_bytes());
+            std::process::exit(libc::EXIT_FAILURE);
+        }
+    }
+}
+
+/// Register the interop entry again, once systemd-binfmt removed it.
+fn restore_interop(config: &Config) {
+    if !config.interop.restore {
+        return;
+    }
+    match binfmt::restore_interop(config) {
+        Ok(true) => log::info!("registered {} again", config.interop.binfmt_name),
+        Ok(false) => (),
+        Err(e) => log::warn!("failed to restore the interop registration: {}", e),
+    }
+}
+
+/// What the boot is still waiting for, `None` once systemd finished booting, or
+/// the units in `units` are active.
+fn get_pending_boot(manager: &mut SystemdManager, units: &[String]) -> Result<Option<String>, DBusError> {
+    if units.is_empty() {
+        let state = manager.system_state()?;
+        log::trace!("system state: {}", state);
+        return match state.as_str() {
+            "running" | "degraded" | "maintenance" => Ok(None),
+            _ => Ok(Some(format!("boot completion(state={})", state))),
+        };
+    }
+    for u in units {
+        let state = match manager.get_unit_active_state(u) {
+            Ok(s) => s,
+            // GetUnit fails until the unit is loaded
+            Err(DBusError::MethodError(..)) => String::new(),
+            Err(e) => return Err(e),
+        };
+        log::trace!("state of {}: {}", u, state);
+        if state != "active" {
+            return Ok(Some(u.clone()));
+        }
+    }
+    Ok(None)
+}
+
+/// Wait until systemd finished booting, or the units in `wait-units` are active.
+fn wait_for_boot(config: &Config, pidfd: &PidFd) -> Result<(), SystemdError> {
+    let pid = pidfd.pid();
+    let start = std::time::Instant::now();
+    let timeout = std::time::Duration::from_secs(config.systemd.boot_timeout);
+    let interval = std::time::Duration::from_millis(100);
+    let units = &config.systemd.wait_units;
+
+    let mut manager = None;
+    loop {
+        if !pidfd.is_alive() {
+            log::error!("systemd(PID={}) exited while booting", pid);
+            return Err(SystemdError::SystemdNotRunning);
+        }
+
+        if manager.is_none() {
+            // the system bus is not available in the early stage of booting
+            manager = SystemdManager::connect(config, Some(pid))
+                .map_err(|e| log::trace!("systemd not reachable through D-Bus: {}", e))
+                .ok();
+        }
+
+        let pending = match manager.as_mut().map(|m| get_pending_boot(m, units)) {
+            None => Some("D-Bus".to_string()),
+            Some(Ok(p)) => p,
+            Some(Err(e)) => {
+                // e.g. the bus daemon restarted while booting, connect again
+                log::trace!("D-Bus connection lost while waiting for boot: {}", e);
+                manager = None;
+                Some(format!("D-Bus ({})", e))
+            }
+        };
+
+        match pending {
+            None => {
+                log::info!("systemd(PID={}) booted", pid);
+                return Ok(());
+            }
+            Some(p) => {
+                if start.elapsed() >= timeout {
+                    log::error!("systemd not booted in time, still waiting for {}", p);
+                    return Err(SystemdError::BootTimeout(p));
+                }
+            }
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+fn exec_systemd(
+    config: &Config,
+    systemd_bin: std::ffi::CString,
+    mount_cgroup2: bool,
+    hostname_file: Option<String>,
+    guarded: &[String],
+    wfd: std::os::unix::io::RawFd,
+) -> ! {
     use std::ffi::OsStr;
     use nix::fcntl::OFlag;
     use nix::mount::MsFlags;
     use nix::sys::stat::Mode;
 
     log::trace!("mounting filesystem");
-    nix::mount::mount(
+    // the mounts are still peers of the ones of WSL, made slaves first so
+    // that the mounts of the bottle, e.g. /etc/hostname, do not propagate
+    // back to WSL, while drives mounted by WSL still propagate here
+    check_child_step(wfd, "making / slave", nix::mount::mount(
+        Some(OsStr::new("none")),
+        OsStr::new("/"),
+        None as Option<&[u8]>,
+        MsFlags::MS_REC | MsFlags::MS_SLAVE,
+        None as Option<&[u8]>,
+    ));
+    check_child_step(wfd, "making / shared", nix::mount::mount(
         Some(OsStr::new("none")),
         OsStr::new("/"),
         None as Option<&[u8]>,
         MsFlags::MS_REC | MsFlags::MS_SHARED,
         None as Option<&[u8]>,
-    )
-    .unwrap();
-    nix::mount::mount(
+    ));
+    check_child_step(wfd, "making /proc private", nix::mount::mount(
         Some(OsStr::new("none")),
         OsStr::new("/proc"),
         None as Option<&[u8]>,
         MsFlags::MS_REC | MsFlags::MS_PRIVATE,
         None as Option<&[u8]>,
-    )
-    .unwrap();
-    nix::mount::mount(
+    ));
+    check_child_step(wfd, "mounting /proc", nix::mount::mount(
         Some(OsStr::new("proc")),
         OsStr::new("/proc"),
         Some(OsStr::new("proc")),
         MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
         None as Option<&[u8]>,
-    )
-    .unwrap();
+    ));
 
-    log::trace!("switch working directory");
-    nix::unistd::chdir("/").unwrap();
+    // rooted at the cgroup of the bottle when the cgroup namespace is enabled
+    if mount_cgroup2 {
+        log::trace!("mounting cgroup2 at {}", cgroup::CGROUP_ROOT);
+        check_child_step(wfd, "mounting cgroup2", nix::mount::mount(
+            Some(OsStr::new("cgroup2")),
+            OsStr::new(cgroup::CGROUP_ROOT),
+            Some(OsStr::new("cgroup2")),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            None as Option<&[u8]>,
+        ));
+    }
 
-    log::trace!("updating STDIN, STDOUT, STDERR");
-    {
-        let fd = nix::fcntl::open(OsStr::new("/dev/null"), OFlag::O_RDONLY, Mode::empty()).unwrap();
-        nix::unistd::dup2(fd, libc::STDIN_FILENO).unwrap();
-        nix::unistd::close(fd).unwrap();
+    // systemd sets the hostname from /etc/hostname while booting
+    if let Some(f) = hostname_file {
+        if std::fs::metadata("/etc/hostname").is_ok() {
+            log::trace!("mounting {} to /etc/hostname", f);
+            check_child_step(wfd, "mounting /etc/hostname", nix::mount::mount(
+                Some(f.as_str()),
+                "/etc/hostname",
+                None as Option<&str>,
+                MsFlags::MS_BIND,
+                None as Option<&str>,
+            ));
+        }
     }
-    {
-        let fd = nix::fcntl::open(OsStr::new("/dev/null"), OFlag::O_WRONLY, Mode::empty()).unwrap();
-        nix::unistd::dup2(fd, libc::STDOUT_FILENO).unwrap();
-        nix::unistd::close(fd).unwrap();
+
+    for (i, m) in config.mounts.iter().enumerate() {
+        check_child_step(wfd, &format!("mount #{} ({})", i + 1, m), mount::apply_mount(m));
     }
-    {
-        let fd = nix::fcntl::open(OsStr::new("/dev/null"), OFlag::O_WRONLY, Mode::empty()).unwrap();
-        nix::unistd::dup2(fd, libc::STDERR_FILENO).unwrap();
-        nix::unistd::close(fd).unwrap();
+
+    // last, so that no mount shadows them
+    for f in guarded {
+        log::trace!("guarding {}", f);
+        check_child_step(wfd, &format!("guarding {}", f), guard::protect(f));
+    }
+
+    log::trace!("switch working directory");
+    check_child_step(wfd, "chdir /", nix::unistd::chdir("/"));
+
+    log::trace!("updating STDIN, STDOUT, STDERR");
+    for (flag, target) in [
+        (OFlag::O_RDONLY, libc::STDIN_FILENO),
+        (OFlag::O_WRONLY, libc::STDOUT_FILENO),
+        (OFlag::O_WRONLY, libc::STDERR_FILENO),
+    ] {
+        let fd = check_child_step(wfd, "opening /dev/null", nix::fcntl::open(OsStr::new("/dev/null"), flag, Mode::empty()));
+        check_child_step(wfd, "dup2", nix::unistd::dup2(fd, target));
+        let _ = nix::unistd::close(fd);
     }
 
     log::trace!("launching systemd");
-    nix::unistd::execve(systemd_bin.as_c_str(), &[systemd_bin.as_c_str()], &[] as &[std::ffi::CString]).unwrap();
+    let Err(e) = nix::unistd::execve(systemd_bin.as_c_str(), &[systemd_bin.as_c_str()], &[] as &[std::ffi::CString]);
+    let step = format!("execve {}", systemd_bin.to_string_lossy());
+    check_child_step(wfd, &step, Err::<(), _>(e));
+    unreachable!();
 }
 
-pub fn stop_systemd() -> Result<(), SystemdError> {
-    if is_associated_with_systemd() {
-        kill_systemd(1)
-    } else if let Some(pid) = get_systemd_pid()? {
-        kill_systemd(pid)
+/// How bottled systemd was stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopResult {
+    NotRunning,
+    /// systemd powered off in time.
+    Clean,
+    /// systemd had to be terminated by SIGTERM or SIGKILL.
+    Forced,
+    /// Power-off requested from inside the bottle, where the exit of systemd
+    /// can not be waited for.
+    Requested,
+}
+
+impl StopResult {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            StopResult::NotRunning | StopResult::Clean => 0,
+            StopResult::Forced => 2,
+            StopResult::Requested => 3,
+        }
+    }
+}
+
+pub fn stop_systemd(config: &Config) -> Result<StopResult, SystemdError> {
+    if is_associated_with_systemd(config) {
+        check_permission()?;
+        power_off_systemd(config, &PidFd::open(1)?);
+        log::warn!("systemd requested to power off, unable to wait from inside the namespace");
+        Ok(StopResult::Requested)
+    } else if let Some(pidfd) = get_systemd_pidfd(config)? {
+        kill_systemd(config, &pidfd)
     } else {
         log::info!("systemd not running");
-        Ok(())
+        if has_pid_file(config) {
+            check_permission()?;
+            log::debug!("cleaning up stale files");
+            cleanup_systemd_files(config)?;
+        }
+        Ok(StopResult::NotRunning)
+    }
+}
+
+fn power_off_systemd(config: &Config, pidfd: &PidFd) {
+    let pid = pidfd.pid();
+    match SystemdManager::connect(config, Some(pid)).and_then(|mut m| m.power_off()) {
+        Ok(_) => log::trace!("requested systemd(PID={}) to power off", pid),
+        Err(e) => {
+            log::debug!("failed to power off through D-Bus: {}", e);
+            log::trace!("sending SIGRTMIN + 4 to systemd(PID={})", pid);
+            if let Err(e) = pidfd.send_signal(libc::SIGRTMIN() + 4) {
+                log::debug!("failed to send SIGRTMIN + 4: {}", e);
+            }
+        }
+    }
+}
+
+/// Whether any process is still inside the PID namespace.
+fn is_namespace_alive(ns: u64) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(e) => e,
+        Err(_) => return true,
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_str().is_some_and(|n| n.bytes().all(|b| b.is_ascii_digit())))
+        // zombies have no namespace anymore, and fail here
+        .any(|e| std::fs::metadata(e.path().join("ns/pid")).is_ok_and(|m| m.ino() == ns))
+}
+
+fn wait_for_namespace_exit(ns: u64, timeout: std::time::Duration) -> bool {
+    let start = std::time::Instant::now();
+    let interval = std::time::Duration::from_millis(100);
+    while is_namespace_alive(ns) {
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        std::thread::sleep(interval);
     }
+    true
 }
 
-fn kill_systemd(pid: libc::pid_t) -> Result<(), SystemdError> {
+/// Wait until systemd exited, and the rest of its PID namespace is gone.
+fn wait_for_exit(pidfd: &PidFd, ns: u64, timeout: std::time::Duration) -> Result<bool, SystemdError> {
+    let start = std::time::Instant::now();
+    if !pidfd.wait_exit(timeout)? {
+        return Ok(false);
+    }
+    // the kernel kills the others once the init of the namespace exited
+    Ok(wait_for_namespace_exit(ns, timeout.saturating_sub(start.elapsed())))
+}
+
+fn kill_systemd(config: &Config, pidfd: &PidFd) -> Result<StopResult, SystemdError> {
     check_permission()?;
 
-    log::trace!("sending SIGRTMIN + 4 to systemd(PID={})", pid);
-    unsafe { nix::libc::kill(pid, libc::SIGRTMIN() + 4); }
+    let pid = pidfd.pid();
+    let ns = get_namespace_id(pid, "pid")?;
+    let stop_timeout = std::time::Duration::from_secs(config.systemd.stop_timeout);
+    let kill_timeout = std::time::Duration::from_secs(config.systemd.kill_timeout);
+
+    power_off_systemd(config, pidfd);
+
+    let mut result = StopResult::Clean;
+    let mut stopped = wait_for_exit(pidfd, ns, stop_timeout)?;
+    for (signal, name) in [(libc::SIGTERM, "SIGTERM"), (libc::SIGKILL, "SIGKILL")] {
+        if stopped {
+            break;
+        }
+        result = StopResult::Forced;
+
+        // signals only reach systemd, the kernel kills the rest of the
+        // namespace once it exited, and the cgroup holds the rest of the bottle
+        if !pidfd.is_alive() {
+            log::warn!("processes left in the namespace of systemd(PID={}), killing them", pid);
+            let start = std::time::Instant::now();
+            if let Err(e) = cgroup::kill_bottle_cgroup(config, kill_timeout) {
+                log::warn!("failed to kill the cgroup of the bottle: {}", e);
+            }
+            stopped = wait_for_namespace_exit(ns, kill_timeout.saturating_sub(start.elapsed()));
+            continue;
+        }
+        log::warn!("systemd(PID={}) not stopped in time, sending {}", pid, name);
+        match pidfd.send_signal(signal) {
+            // exited meanwhile
+            Ok(_) | Err(nix::errno::Errno::ESRCH) => (),
+            Err(e) => return Err(SystemdError::NixErrno(e)),
+        }
+        stopped = wait_for_exit(pidfd, ns, kill_timeout)?;
+    }
+    if !stopped {
+        log::error!("processes of systemd(PID={}) still running after SIGKILL", pid);
+        return Err(SystemdError::SystemdStillRunning(pid));
+    }
+
+    cleanup_systemd_files(config)?;
 
-    if std::fs::metadata(PID_FILE).is_ok() {
-        log::trace!("removing {}", PID_FILE);
-        std::fs::remove_file(PID_FILE)?;
+    match result {
+        StopResult::Clean => log::info!("systemd stopped"),
+        _ => log::warn!("systemd stopped forcibly"),
+    }
+    Ok(result)
+}
+
+pub fn restart_systemd(config: &Config) -> Result<(), SystemdError> {
+    if is_associated_with_systemd(config) {
+        log::error!("unable to restart systemd from inside the namespace");
+        return Err(SystemdError::InsideNamespace);
     }
 
-    log::info!("systemd stopped");
+    check_permission()?;
+    let _lock = BottleLock::acquire(config)?;
+
+    stop_systemd(config)?;
+    if let Some(pid) = get_systemd_pid(config)? {
+        log::error!("systemd(PID={}) still running", pid);
+        return Err(SystemdError::SystemdStillRunning(pid));
+    }
+    start_systemd_locked(config)?;
     Ok(())
 }
 
-pub fn associate_with_systemd() -> Result<(), SystemdError> {
-    use std::ffi::OsStr;
+/// Rewrite the drop-ins, unit masks and generator, and apply them to the
+/// running systemd.
+pub fn reload_systemd(config: &Config) -> Result<(), SystemdError> {
+    let pid = if is_associated_with_systemd(config) {
+        1
+    } else if let Some(pid) = get_systemd_pid(config)? {
+        pid
+    } else {
+        log::error!("systemd not running");
+        return Err(SystemdError::SystemdNotRunning);
+    };
+    check_permission()?;
+
+    updated_systemd_envs(config)?;
+    units::updated_unit_masks(config)?;
+    updated_generator(config)?;
+
+    let mut manager = SystemdManager::connect(config, Some(pid))?;
+    manager.set_environment(&env::get_preserved_env(config))?;
+    log::trace!("reloading systemd(PID={})", pid);
+    manager.reload()?;
+    restore_interop(config);
+
+    log::info!("systemd(PID={}) reloaded", pid);
+    Ok(())
+}
+
+/// Open the handle of a namespace, falling back to `/proc/<pid>/ns` when
+/// the handle is missing, or does not match the PID file.
+fn open_namespace(config: &Config, identity: &SystemdIdentity, ns: &str) -> Result<std::os::unix::io::RawFd, SystemdError> {
     use nix::fcntl::OFlag;
     use nix::sys::stat::Mode;
-    use nix::sched::CloneFlags;
 
+    let recorded = match ns {
+        "pid" => identity.pid_namespace,
+        "mnt" => identity.mnt_namespace,
+        _ => None,
+    };
+    let handle = get_namespace_path(config, ns);
+    if let Ok(fd) = nix::fcntl::open(handle.as_str(), OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty()) {
+        let ino = nix::sys::stat::fstat(fd)?.st_ino;
+        if recorded.is_none_or(|r| r == ino) {
+            log::trace!("opened {}", handle);
+            return Ok(fd);
+        }
+        log::warn!("{} does not match the PID file, ignored", handle);
+        nix::unistd::close(fd)?;
+    }
+
+    let path = format!("/proc/{}/ns/{}", identity.pid, ns);
+    log::trace!("opened {}", path);
+    Ok(nix::fcntl::open(path.as_str(), OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())?)
+}
+
+pub fn associate_with_systemd(config: &Config) -> Result<(), SystemdError> {
     check_permission()?;
 
-    match get_systemd_pid() {
-        Ok(Some(pid)) => {
-            log::trace!("associating PID namespace");
-            {
-                let path = format!("/proc/{}/ns/pid", pid);
-                let fd = nix::fcntl::open(OsStr::new(&path), OFlag::O_RDONLY, Mode::empty())?;
-                nix::sched::setns(fd, CloneFlags::CLONE_NEWPID)?;
-                nix::unistd::close(fd)?;
-            }
+    let (identity, _pidfd) = match open_systemd(config)? {
+        Some(s) => s,
+        None => return Err(SystemdError::SystemdNotRunning),
+    };
 
-            log::trace!("associating MNT namespace");
-            {
-                let path = format!("/proc/{}/ns/mnt", pid);
-                let fd = nix::fcntl::open(OsStr::new(&path), OFlag::O_RDONLY, Mode::empty())?;
-                nix::sched::setns(fd, CloneFlags::CLONE_NEWNS)?;
-                nix::unistd::close(fd)?;
-            }
+    // handles are only visible outside the mount namespace, open them all
+    // before joining any
+    let mut fds = Vec::new();
+    for (ns, flag) in NAMESPACES {
+        let fd = open_namespace(config, &identity, ns)?;
+        // namespaces not enabled are shared with us
+        if get_namespace_id(nix::unistd::getpid().as_raw(), ns)? == nix::sys::stat::fstat(fd)?.st_ino {
+            log::trace!("{} namespace is shared", ns);
+            nix::unistd::close(fd)?;
+            continue;
+        }
+        fds.push((ns, flag, fd));
+    }
+    for (ns, flag, fd) in fds {
+        log::trace!("associating {} namespace", ns);
+        let result = nix::sched::setns(fd, flag);
+        nix::unistd::close(fd)?;
+        result?;
+    }
 
-            log::trace!("switch working directory");
-            nix::unistd::chdir("/").unwrap();
+    log::trace!("switch working directory");
+    nix::unistd::chdir("/").unwrap();
 
-            Ok(())
-        },
-        Ok(None) => Err(SystemdError::SystemdNotRunning),
-        Err(e) => Err(e),
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_round_trip() {
+        let identity = SystemdIdentity {
+            pid: 4242,
+            start_time: Some(123456),
+            pid_namespace: Some(4026532281),
+            mnt_namespace: Some(4026532280),
+        };
+        assert_eq!(
+            identity.format(),
+            "4242\nstart-time=123456\npid-namespace=4026532281\nmnt-namespace=4026532280\n"
+        );
+        assert_eq!(SystemdIdentity::parse(&identity.format()).unwrap(), identity);
+
+        // PID files of older versions
+        let bare = SystemdIdentity { pid: 4242, start_time: None, pid_namespace: None, mnt_namespace: None };
+        assert_eq!(bare.format(), "4242\n");
+        assert_eq!(SystemdIdentity::parse("4242").unwrap(), bare);
+        assert_eq!(SystemdIdentity::parse("4242\nunknown=1\n").unwrap(), bare);
+    }
+
+    #[test]
+    fn test_identity_malformed() {
+        assert!(SystemdIdentity::parse("").is_err());
+        assert!(SystemdIdentity::parse("42a\n").is_err());
+        assert!(SystemdIdentity::parse("42\nstart-time=x\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_proc_start_time() {
+        let stat = "42 (evil) 0 (x) S 1 42 42 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 123456 4096 100\n";
+        assert_eq!(parse_proc_start_time(stat).unwrap(), 123456);
+        assert!(parse_proc_start_time("42 (sh) S 1 42\n").is_err());
+        assert!(parse_proc_start_time("42 sh").is_err());
+        assert!(get_proc_start_time(std::process::id() as libc::pid_t).unwrap() > 0);
+    }
+
+    #[test]
+    fn test_unparsable_pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.bottle.pid_file = dir.path().join("systemd.pid").to_string_lossy().to_string();
+        assert!(!has_pid_file(&config));
+        assert!(read_systemd_identity(&config).unwrap().is_none());
+
+        std::fs::write(&config.bottle.pid_file, "not a PID\n").unwrap();
+        assert!(has_pid_file(&config));
+        assert!(read_systemd_identity(&config).unwrap().is_none());
+        assert!(is_pid_file_stale(&config).unwrap());
     }
 }
@@ -0,0 +1,219 @@
+//! Runtime masks of the units failing, or doing damage, under WSL.
+//!
+//! Masks are symlinks to `/dev/null` under `/run/systemd/system`, the ones
+//! written by bottled are recorded so that they are removed once they are
+//! no longer configured, or the bottle is stopped.
+
+use crate::config::Config;
+
+/// Directory of runtime units, as seen by systemd in the bottle.
+pub static RUNTIME_UNIT_DIR: &str = "/run/systemd/system";
+
+/// Units masked unless listed in `unmask`.
+pub static BUILTIN_MASKS: [&str; 6] = [
+    // the root filesystem is managed by WSL
+    "systemd-remount-fs.service",
+    // WSL configures the network before the bottle is started
+    "systemd-networkd-wait-online.service",
+    // replaces /etc/resolv.conf generated by WSL
+    "systemd-resolved.service",
+    // there is no console
+    "getty@tty1.service",
+    // the kernel of WSL has no loadable modules, and sysctls are shared
+    // with every distro
+    "systemd-modules-load.service",
+    "systemd-sysctl.service",
+];
+
+#[derive(thiserror::Error, Debug)]
+pub enum UnitError {
+    #[error("invalid unit name {0}")]
+    InvalidUnit(String),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+/// Check a unit name, masks must stay in the unit directory.
+pub fn check_unit_name(name: &str) -> Result<(), UnitError> {
+    let valid = name
+        .rsplit_once('.')
+        .is_some_and(|(n, t)| !n.is_empty() && !t.is_empty())
+        && !name.contains('/')
+        && !name.starts_with('.');
+    if !valid {
+        return Err(UnitError::InvalidUnit(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Built-in masks left out when `bottled generator` is installed, its
+/// drop-ins only keep them from replacing the files generated by WSL.
+static GENERATOR_UNITS: [&str; 1] = ["systemd-resolved.service"];
+
+/// Units to mask, the built-in ones not in `unmask`, and the ones in `mask`.
+pub fn get_masked_units(config: &Config) -> Vec<String> {
+    let units = &config.units;
+    let mut masked: Vec<String> = BUILTIN_MASKS
+        .iter()
+        .filter(|u| !units.unmask.iter().any(|n| n == *u))
+        .filter(|u| !(config.systemd.generator && GENERATOR_UNITS.contains(u)))
+        .map(|u| u.to_string())
+        .collect();
+    for u in &units.mask {
+        if !masked.contains(u) {
+            masked.push(u.clone());
+        }
+    }
+    masked
+}
+
+fn is_mask(path: &std::path::Path) -> bool {
+    std::fs::read_link(path).is_ok_and(|target| target == std::path::Path::new("/dev/null"))
+}
+
+fn read_record(record: &str) -> Result<Vec<String>, UnitError> {
+    match std::fs::read_to_string(record) {
+        Ok(content) => Ok(content.lines().map(|l| l.to_string()).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(UnitError::IOError(e)),
+    }
+}
+
+/// Remove the masks listed in `record` which are not in `keep`, units
+/// replaced since are left alone.
+fn remove_stale_masks(dir: &str, record: &str, keep: &[String]) -> Result<(), UnitError> {
+    for unit in read_record(record)? {
+        if keep.contains(&unit) || check_unit_name(&unit).is_err() {
+            continue;
+        }
+        let path = std::path::Path::new(dir).join(&unit);
+        if is_mask(&path) {
+            log::trace!("unmasking {}", unit);
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Mask `units` in `dir`, and record the masks in `record`. Masks no longer
+/// listed are removed, and existing units in `dir` are not replaced.
+pub fn write_masks(dir: &str, record: &str, units: &[String]) -> Result<(), UnitError> {
+    for u in units {
+        check_unit_name(u)?;
+    }
+    remove_stale_masks(dir, record, units)?;
+
+    std::fs::create_dir_all(dir)?;
+    let mut masked = Vec::new();
+    for unit in units {
+        let path = std::path::Path::new(dir).join(unit);
+        match std::fs::symlink_metadata(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::trace!("masking {}", unit);
+                std::os::unix::fs::symlink("/dev/null", &path)?;
+                masked.push(unit.as_str());
+            }
+            Ok(_) if is_mask(&path) => masked.push(unit.as_str()),
+            Ok(_) => log::warn!("{} exists, not masking {}", path.display(), unit),
+            Err(e) => return Err(UnitError::IOError(e)),
+        }
+    }
+
+    let content: String = masked.iter().map(|u| format!("{}\n", u)).collect();
+    std::fs::write(record, content)?;
+    Ok(())
+}
+
+/// Remove every mask recorded in `record`, and the record.
+pub fn remove_masks(dir: &str, record: &str) -> Result<(), UnitError> {
+    remove_stale_masks(dir, record, &[])?;
+    if std::fs::metadata(record).is_ok() {
+        std::fs::remove_file(record)?;
+    }
+    Ok(())
+}
+
+fn get_record_file(config: &Config) -> String {
+    format!("{}/masked-units", config.bottle.run_dir)
+}
+
+/// Write the masks configured for the bottle.
+pub fn updated_unit_masks(config: &Config) -> Result<(), UnitError> {
+    std::fs::create_dir_all(&config.bottle.run_dir)?;
+    write_masks(RUNTIME_UNIT_DIR, &get_record_file(config), &get_masked_units(config))
+}
+
+/// Remove the masks of the bottle, once it is stopped.
+pub fn remove_unit_masks(config: &Config) -> Result<(), UnitError> {
+    remove_masks(RUNTIME_UNIT_DIR, &get_record_file(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_units(units: &[&str]) -> Vec<String> {
+        units.iter().map(|u| u.to_string()).collect()
+    }
+
+    #[test]
+    fn test_write_masks() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("system");
+        let dir = dir.to_str().unwrap();
+        let record = root.path().join("masked-units");
+        let record = record.to_str().unwrap();
+
+        std::fs::create_dir(dir).unwrap();
+        std::fs::write(format!("{}/getty@tty1.service", dir), "[Unit]\n").unwrap();
+        write_masks(dir, record, &get_units(&["a.service", "b.service", "getty@tty1.service"])).unwrap();
+        assert!(is_mask(&std::path::Path::new(dir).join("a.service")));
+        assert!(is_mask(&std::path::Path::new(dir).join("b.service")));
+        assert_eq!(std::fs::read_to_string(format!("{}/getty@tty1.service", dir)).unwrap(), "[Unit]\n");
+        assert_eq!(std::fs::read_to_string(record).unwrap(), "a.service\nb.service\n");
+
+        // b.service is no longer configured, and c.service replaced since
+        std::os::unix::fs::symlink("/dev/null", format!("{}/c.service", dir)).unwrap();
+        write_masks(dir, record, &get_units(&["a.service"])).unwrap();
+        assert!(is_mask(&std::path::Path::new(dir).join("a.service")));
+        assert!(std::fs::symlink_metadata(format!("{}/b.service", dir)).is_err());
+        assert!(is_mask(&std::path::Path::new(dir).join("c.service")));
+        assert_eq!(std::fs::read_to_string(record).unwrap(), "a.service\n");
+
+        assert!(write_masks(dir, record, &get_units(&["../a.service"])).is_err());
+    }
+
+    #[test]
+    fn test_remove_masks() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().to_str().unwrap();
+        let record = root.path().join("masked-units");
+        let record = record.to_str().unwrap();
+
+        write_masks(dir, record, &get_units(&["a.service", "b.service"])).unwrap();
+        // replaced by a unit since
+        std::fs::remove_file(format!("{}/b.service", dir)).unwrap();
+        std::fs::write(format!("{}/b.service", dir), "[Unit]\n").unwrap();
+        remove_masks(dir, record).unwrap();
+        assert!(std::fs::symlink_metadata(format!("{}/a.service", dir)).is_err());
+        assert!(std::fs::metadata(format!("{}/b.service", dir)).is_ok());
+        assert!(std::fs::metadata(record).is_err());
+        remove_masks(dir, record).unwrap();
+    }
+
+    #[test]
+    fn test_get_masked_units() {
+        let mut config = Config::default();
+        config.units.unmask = get_units(&["getty@tty1.service"]);
+        config.units.mask = get_units(&["systemd-sysctl.service", "a.service"]);
+        let masked = get_masked_units(&config);
+        assert!(!masked.contains(&"getty@tty1.service".to_string()));
+        assert!(!masked.contains(&"systemd-resolved.service".to_string()));
+        assert_eq!(masked.iter().filter(|u| *u == "systemd-sysctl.service").count(), 1);
+        assert_eq!(masked.last().unwrap(), "a.service");
+
+        config.systemd.generator = false;
+        assert!(get_masked_units(&config).contains(&"systemd-resolved.service".to_string()));
+    }
+}
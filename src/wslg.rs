@@ -0,0 +1,254 @@
+//! Sockets of WSLg in bottled sessions.
+//!
+//! WSLg serves Wayland and PulseAudio from `/mnt/wslg/runtime-dir`, and X11
+//! from `/mnt/wslg/.X11-unix`. Sessions of the bottle get their own runtime
+//! directory from logind, and `/tmp` may be mounted by systemd, so the
+//! sockets are linked into the runtime directory of each user, and
+//! `/tmp/.X11-unix` is bound inside the bottle.
+
+use crate::config::{Config, MountConfig, MountType};
+use crate::mount::{self, MountError};
+
+/// Directory of X11 sockets, as seen by clients.
+pub static X11_SOCKET_DIR: &str = "/tmp/.X11-unix";
+
+#[derive(thiserror::Error, Debug)]
+pub enum WslgError {
+    #[error("{0} is not a directory")]
+    NotADirectory(String),
+
+    #[error(transparent)]
+    MountError(#[from] MountError),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    NixErrno(#[from] nix::errno::Errno),
+}
+
+/// Whether an entry of the runtime directory of WSLg is linked, its other
+/// entries, e.g. the D-Bus socket, would conflict with the ones of the user.
+fn is_linked(name: &str) -> bool {
+    name.starts_with("wayland-") || name == "pulse"
+}
+
+/// Link the sockets in `<wslg_dir>/runtime-dir` into `runtime_dir`, owned by
+/// `uid` and `gid`. Existing entries are left alone, returns the linked ones.
+pub fn link_runtime_dir(
+    wslg_dir: &str,
+    runtime_dir: &str,
+    uid: nix::unistd::Uid,
+    gid: nix::unistd::Gid,
+) -> Result<Vec<String>, WslgError> {
+    use nix::unistd::FchownatFlags;
+
+    let source = std::path::Path::new(wslg_dir).join("runtime-dir");
+    let mut linked = Vec::new();
+    for entry in std::fs::read_dir(&source)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if !is_linked(&name) {
+            continue;
+        }
+        let link = std::path::Path::new(runtime_dir).join(&name);
+        if std::fs::symlink_metadata(&link).is_ok() {
+            log::debug!("{} exists, not linking", link.display());
+            continue;
+        }
+        log::trace!("linking {} to {}", link.display(), source.join(&name).display());
+        std::os::unix::fs::symlink(source.join(&name), &link)?;
+        nix::unistd::fchownat(None, &link, Some(uid), Some(gid), FchownatFlags::NoFollowSymlink)?;
+        linked.push(name);
+    }
+    Ok(linked)
+}
+
+/// Open the directory at `path`, refusing symlinks.
+fn open_dir(path: &str) -> Result<std::fs::File, WslgError> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(path)
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ELOOP) | Some(libc::ENOTDIR) => WslgError::NotADirectory(path.to_string()),
+            _ => WslgError::IOError(e),
+        })
+}
+
+/// Bind `<wslg_dir>/.X11-unix` read-only on `x11_dir`, unless it is already.
+/// Returns whether it was bound.
+///
+/// `x11_dir` is under `/tmp`, writable by anyone, so symlinks are refused,
+/// and the directory is held open from the checks until it is mounted on.
+pub fn bind_x11(wslg_dir: &str, x11_dir: &str) -> Result<bool, WslgError> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    use std::os::unix::io::AsRawFd;
+    use nix::mount::MsFlags;
+
+    let source = format!("{}/.X11-unix", wslg_dir);
+    let sockets = std::fs::symlink_metadata(&source)?;
+    if !sockets.is_dir() {
+        return Err(WslgError::NotADirectory(source));
+    }
+
+    // mkdir does not follow a symlink at x11_dir
+    let created = match std::fs::DirBuilder::new().mode(0o1777).create(x11_dir) {
+        Ok(()) => true,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => false,
+        Err(e) => return Err(WslgError::IOError(e)),
+    };
+    let dir = open_dir(x11_dir)?;
+    let m = dir.metadata()?;
+    if m.dev() == sockets.dev() && m.ino() == sockets.ino() {
+        return Ok(false);
+    }
+    if created {
+        // the mode passed to mkdir is masked by umask
+        dir.set_permissions(std::fs::Permissions::from_mode(0o1777))?;
+    }
+
+    log::trace!("binding {} to {}", source, x11_dir);
+    let m = MountConfig {
+        kind: MountType::Bind,
+        source: Some(source),
+        target: format!("/proc/self/fd/{}", dir.as_raw_fd()),
+        options: None,
+        read_only: false,
+        recursive: false,
+        propagation: None,
+    };
+    mount::apply_mount(&m)?;
+    // the open directory is the one below the mount, only the root of a
+    // mount can be remounted, which can not be replaced anymore
+    let bound = open_dir(x11_dir)?;
+    nix::mount::mount(
+        None as Option<&str>,
+        format!("/proc/self/fd/{}", bound.as_raw_fd()).as_str(),
+        None as Option<&str>,
+        MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY,
+        None as Option<&str>,
+    )?;
+    Ok(true)
+}
+
+fn is_available(config: &Config) -> bool {
+    config.wslg.enable && std::fs::metadata(&config.wslg.path).is_ok()
+}
+
+/// Set up X11 sockets inside the bottle, for sessions without a runtime
+/// directory.
+pub fn setup_x11(config: &Config) -> Result<(), WslgError> {
+    if !is_available(config) || std::fs::metadata(format!("{}/.X11-unix", config.wslg.path)).is_err() {
+        return Ok(());
+    }
+    if bind_x11(&config.wslg.path, X11_SOCKET_DIR)? {
+        log::debug!("bound {}/.X11-unix to {}", config.wslg.path, X11_SOCKET_DIR);
+    }
+    Ok(())
+}
+
+/// Set up the sockets of WSLg for the user `uid`, inside the bottle.
+pub fn setup_user(config: &Config, uid: nix::unistd::Uid) -> Result<(), WslgError> {
+    if !is_available(config) {
+        return Ok(());
+    }
+    setup_x11(config)?;
+
+    let runtime_dir = format!("/run/user/{}", uid);
+    let owner = match std::fs::metadata(&runtime_dir) {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::debug!("{} not created yet", runtime_dir);
+            return Ok(());
+        }
+        Err(e) => return Err(WslgError::IOError(e)),
+    };
+    if std::fs::metadata(format!("{}/runtime-dir", config.wslg.path)).is_ok() {
+        use std::os::unix::fs::MetadataExt;
+
+        let gid = nix::unistd::Gid::from_raw(owner.gid());
+        let linked = link_runtime_dir(&config.wslg.path, &runtime_dir, uid, gid)?;
+        log::debug!("linked {:?} into {}", linked, runtime_dir);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_runtime_dir() {
+        let wslg = tempfile::tempdir().unwrap();
+        let runtime_dir = tempfile::tempdir().unwrap();
+        let source = wslg.path().join("runtime-dir");
+        std::fs::create_dir_all(source.join("pulse")).unwrap();
+        for name in ["wayland-0", "wayland-0.lock", "dbus-1"] {
+            std::fs::write(source.join(name), "").unwrap();
+        }
+        // e.g. started by the user already
+        std::fs::write(runtime_dir.path().join("wayland-0.lock"), "").unwrap();
+
+        let (uid, gid) = (nix::unistd::getuid(), nix::unistd::getgid());
+        let wslg_dir = wslg.path().to_string_lossy().to_string();
+        let runtime = runtime_dir.path().to_string_lossy().to_string();
+        let mut linked = link_runtime_dir(&wslg_dir, &runtime, uid, gid).unwrap();
+        linked.sort();
+        assert_eq!(linked, vec!["pulse".to_string(), "wayland-0".to_string()]);
+        for name in &linked {
+            assert_eq!(std::fs::read_link(runtime_dir.path().join(name)).unwrap(), source.join(name));
+        }
+        assert!(std::fs::symlink_metadata(runtime_dir.path().join("dbus-1")).is_err());
+        assert!(std::fs::read_link(runtime_dir.path().join("wayland-0.lock")).is_err());
+
+        // existing links are left alone
+        assert!(link_runtime_dir(&wslg_dir, &runtime, uid, gid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bind_x11_refused() {
+        let wslg = tempfile::tempdir().unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let wslg_dir = wslg.path().to_string_lossy().to_string();
+        let x11_dir = tmp.path().join(".X11-unix");
+        let x11 = x11_dir.to_string_lossy().to_string();
+
+        // WSLg without X11
+        assert!(matches!(bind_x11(&wslg_dir, &x11), Err(WslgError::IOError(_))));
+        assert!(std::fs::symlink_metadata(&x11_dir).is_err());
+
+        std::fs::create_dir(wslg.path().join(".X11-unix")).unwrap();
+        let elsewhere = tmp.path().join("elsewhere");
+        std::fs::create_dir(&elsewhere).unwrap();
+        std::os::unix::fs::symlink(&elsewhere, &x11_dir).unwrap();
+        assert!(matches!(bind_x11(&wslg_dir, &x11), Err(WslgError::NotADirectory(_))));
+        // dangling as well
+        std::fs::remove_dir(&elsewhere).unwrap();
+        assert!(matches!(bind_x11(&wslg_dir, &x11), Err(WslgError::NotADirectory(_))));
+        std::fs::remove_file(&x11_dir).unwrap();
+        std::fs::write(&x11_dir, "").unwrap();
+        assert!(matches!(bind_x11(&wslg_dir, &x11), Err(WslgError::NotADirectory(_))));
+    }
+
+    #[test]
+    fn test_bind_x11_source_symlink() {
+        let wslg = tempfile::tempdir().unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(tmp.path(), wslg.path().join(".X11-unix")).unwrap();
+        let x11 = tmp.path().join(".X11-unix").to_string_lossy().to_string();
+        let result = bind_x11(&wslg.path().to_string_lossy(), &x11);
+        assert!(matches!(result, Err(WslgError::NotADirectory(_))));
+    }
+
+    #[test]
+    fn test_bind_x11_bound() {
+        let wslg = tempfile::tempdir().unwrap();
+        let sockets = wslg.path().join(".X11-unix");
+        std::fs::create_dir(&sockets).unwrap();
+        // the same directory as the sockets of WSLg, as once bound
+        let bound = bind_x11(&wslg.path().to_string_lossy(), &sockets.to_string_lossy()).unwrap();
+        assert!(!bound);
+    }
+}